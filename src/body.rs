@@ -0,0 +1,215 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use actix_web::error::PayloadError;
+use actix_web::web::{Bytes, BytesMut, Payload};
+use futures_util::stream::{self, Stream, StreamExt};
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How many chunks of a streamed request body can be waiting to go upstream
+/// before we stop reading from the client.
+const STREAM_BUFFER_CHUNKS: usize = 8;
+
+/// Reads the whole request body into memory.  Fails with a `TimedOut` error
+/// if it isn't all there within `limit`.
+pub async fn buffer(mut payload: Payload, limit: Option<Duration>) -> Result<Bytes, PayloadError> {
+    let deadline = limit.map(|limit| Instant::now() + limit);
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = next_chunk(&mut payload, deadline).await {
+        body.extend_from_slice(&chunk?);
+    }
+
+    Ok(body.freeze())
+}
+
+/// Turns the request body into one that reqwest can send as it arrives.
+/// actix's payload can't leave the worker thread, so a local task reads it
+/// and hands the chunks over through a channel.  The body ends with a
+/// `TimedOut` error if it isn't all there within `limit`, and an `Overflow`
+/// one once it's over `max` bytes.
+pub fn stream(mut payload: Payload, limit: Option<Duration>, max: Option<u64>) -> reqwest::Body {
+    let (tx, rx) = mpsc::channel::<Result<Bytes, PayloadError>>(STREAM_BUFFER_CHUNKS);
+    let deadline = limit.map(|limit| Instant::now() + limit);
+
+    actix_web::rt::spawn(async move {
+        let mut read = 0;
+
+        while let Some(mut chunk) = next_chunk(&mut payload, deadline).await {
+            if let Ok(bytes) = &chunk {
+                read += bytes.len() as u64;
+
+                if max.is_some_and(|max| read > max) {
+                    chunk = Err(PayloadError::Overflow);
+                }
+            }
+
+            let failed = chunk.is_err();
+
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    reqwest::Body::wrap_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// The next chunk of the request body, or a `TimedOut` error once it's past
+/// `deadline`.
+async fn next_chunk(
+    payload: &mut Payload,
+    deadline: Option<Instant>,
+) -> Option<Result<Bytes, PayloadError>> {
+    let Some(deadline) = deadline else {
+        return payload.next().await;
+    };
+
+    match tokio::time::timeout_at(deadline.into(), payload.next()).await {
+        Ok(next) => next,
+        Err(_) => Some(Err(PayloadError::Io(io::ErrorKind::TimedOut.into()))),
+    }
+}
+
+/// Whether `err` (or what caused it) is the `TimedOut` error a request body
+/// ends with when the client is too slow.
+pub fn is_timed_out(err: &(dyn Error + 'static)) -> bool {
+    caused_by(
+        err,
+        |err| matches!(err, PayloadError::Io(err) if err.kind() == io::ErrorKind::TimedOut),
+    )
+}
+
+/// Whether `err` (or what caused it) is the `Overflow` error a streamed
+/// request body ends with when it's too big.
+pub fn is_too_large(err: &(dyn Error + 'static)) -> bool {
+    caused_by(err, |err| matches!(err, PayloadError::Overflow))
+}
+
+/// Whether `err` or anything that caused it is a `PayloadError` that passes
+/// `test`.
+fn caused_by(err: &(dyn Error + 'static), test: impl Fn(&PayloadError) -> bool) -> bool {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        if err.downcast_ref::<PayloadError>().is_some_and(&test) {
+            return true;
+        }
+
+        source = err.source();
+    }
+
+    false
+}
+
+/// Passes a response body on, ending it with a `TimedOut` error if the backend
+/// goes `limit` without sending anything.
+pub fn read_timeout(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Unpin,
+    limit: Option<Duration>,
+    id: String,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(Some(body), move |body| {
+        let id = id.clone();
+
+        async move {
+            let mut body = body?;
+
+            let next = match limit {
+                Some(limit) => match tokio::time::timeout(limit, body.next()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        log!(
+                            "WARN: [{id}] Backend sent nothing for {}s, giving up on it",
+                            limit.as_secs()
+                        );
+                        let err = io::Error::new(io::ErrorKind::TimedOut, "Backend stalled");
+                        return Some((Err(err), None));
+                    }
+                },
+                None => body.next().await,
+            };
+
+            match next? {
+                Ok(chunk) => Some((Ok(chunk), Some(body))),
+                Err(err) => Some((Err(io::Error::other(err)), None)),
+            }
+        }
+    })
+}
+
+/// Passes a response body on, ending it with an error once more than `max`
+/// bytes have come through.  For bodies of unknown length, which can't be
+/// turned away before they're started.
+pub fn capped(
+    body: impl Stream<Item = io::Result<Bytes>>,
+    max: u64,
+    id: String,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::unfold(Some((Box::pin(body), 0)), move |state| {
+        let id = id.clone();
+
+        async move {
+            let (mut body, sent) = state?;
+
+            match body.next().await? {
+                Ok(chunk) if sent + chunk.len() as u64 > max => {
+                    log!("ERROR: [{id}] Upstream response went over {max} bytes, cutting it off");
+                    let err = io::Error::other("Upstream response too large");
+                    Some((Err(err), None))
+                }
+                Ok(chunk) => {
+                    let sent = sent + chunk.len() as u64;
+                    Some((Ok(chunk), Some((body, sent))))
+                }
+                Err(err) => Some((Err(err), None)),
+            }
+        }
+    })
+}
+
+/// A response body that stopped short, e.g. because the backend closed the
+/// connection before sending all of its Content-Length.
+pub struct Incomplete {
+    pub error: io::Error,
+    /// How much of it did arrive
+    pub received: usize,
+}
+
+/// Reads a whole response body (through `read_timeout`) into memory.
+pub async fn collect(body: impl Stream<Item = io::Result<Bytes>>) -> Result<Bytes, Incomplete> {
+    let mut body = Box::pin(body);
+    let mut collected = BytesMut::new();
+
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => collected.extend_from_slice(&chunk),
+            Err(error) => {
+                return Err(Incomplete {
+                    error,
+                    received: collected.len(),
+                })
+            }
+        }
+    }
+
+    Ok(collected.freeze())
+}
@@ -0,0 +1,48 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use clap::Parser;
+
+/// Intentionally dumb Docker name-based HTTP router.
+///
+/// Everything can also be set through environment variables (see the
+/// README); flags win over those, which win over the config file.
+#[derive(Parser, Debug, Default)]
+#[command(version = crate::VERSION)]
+pub struct Args {
+    /// Address to listen on [env: DUMBROUTER_BIND] [default: 0.0.0.0:8080]
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<String>,
+
+    /// Docker (or Podman) socket or URL to find containers through
+    /// [env: DUMBROUTER_DOCKER_SOCKET]
+    #[arg(long, value_name = "PATH")]
+    pub docker_socket: Option<String>,
+
+    /// Host that published container ports are reachable on [env:
+    /// LOCALHOST_IP] [default: host.docker.internal]
+    #[arg(long, value_name = "HOST")]
+    pub localhost_ip: Option<String>,
+
+    /// Config file to read [env: DUMBROUTER_CONFIG]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// `warn` logs everything, `error` only errors [env:
+    /// DUMBROUTER_LOG_LEVEL] [default: warn]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+}
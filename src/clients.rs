@@ -0,0 +1,190 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Config;
+use crate::discovery::Backend;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Label that turns off certificate verification for one HTTPS backend.
+const TLS_INSECURE_LABEL: &str = "dumbrouter.tls_insecure";
+
+/// Label naming a PEM file with a CA certificate to trust for one backend, on
+/// top of the usual roots.
+const TLS_CA_LABEL: &str = "dumbrouter.tls_ca";
+
+/// How many differently-configured clients are kept around.  There should
+/// only ever be a handful, so hitting this means something's churning.
+const MAX_CLIENTS: usize = 32;
+
+/// What a backend needs from the client's TLS setup.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+struct TlsSettings {
+    insecure: bool,
+    ca: Option<String>,
+}
+
+impl TlsSettings {
+    fn for_backend(backend: &Backend) -> Self {
+        TlsSettings {
+            insecure: backend.label(TLS_INSECURE_LABEL) == Some("true"),
+            ca: backend.label(TLS_CA_LABEL).map(String::from),
+        }
+    }
+}
+
+/// The HTTP clients used to talk to backends.  Most backends share the
+/// default one; backends with their own TLS settings share one per distinct
+/// set of settings, so that connection pooling still works for them.
+pub struct Clients {
+    pooling: Pooling,
+    pools: Mutex<Pools>,
+}
+
+/// How clients keep unused connections around.
+#[derive(Clone, Copy)]
+struct Pooling {
+    idle_timeout: Duration,
+    max_idle: Option<usize>,
+}
+
+/// Everything that holds pooled connections, and the discovery generation it
+/// was built for.
+struct Pools {
+    generation: u64,
+    default: reqwest::Client,
+    custom: HashMap<TlsSettings, reqwest::Client>,
+}
+
+impl Clients {
+    pub fn new(config: &Config) -> Self {
+        let pooling = Pooling {
+            idle_timeout: config.pool_idle_timeout,
+            max_idle: config.pool_max_idle,
+        };
+        let default =
+            build(&TlsSettings::default(), pooling).expect("default client can always be built");
+
+        Clients {
+            pooling,
+            pools: Mutex::new(Pools {
+                generation: 0,
+                default,
+                custom: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The client to use for `backend`, as of discovery `generation`.  Fails
+    /// if its TLS settings can't be used, e.g. the CA file can't be read.
+    pub fn for_backend(
+        &self,
+        backend: &Backend,
+        generation: u64,
+    ) -> Result<reqwest::Client, String> {
+        let settings = TlsSettings::for_backend(backend);
+        let mut pools = self.pools.lock().unwrap();
+
+        // A backend went away.  reqwest can't drop one host's connections, so
+        // start over with fresh pools rather than risk reusing a connection to
+        // a container that's gone (or to whatever took over its port)
+        if pools.generation != generation {
+            pools.generation = generation;
+            pools.default = build(&TlsSettings::default(), self.pooling)?;
+            pools.custom.clear();
+        }
+
+        if settings == TlsSettings::default() {
+            return Ok(pools.default.clone());
+        }
+
+        if let Some(client) = pools.custom.get(&settings) {
+            return Ok(client.clone());
+        }
+
+        let client = build(&settings, self.pooling)?;
+
+        if pools.custom.len() >= MAX_CLIENTS {
+            log!("WARN: Too many distinct backend TLS settings, dropping cached clients");
+            pools.custom.clear();
+        }
+        pools.custom.insert(settings, client.clone());
+
+        Ok(client)
+    }
+}
+
+fn build(settings: &TlsSettings, pooling: Pooling) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder().danger_accept_invalid_certs(settings.insecure);
+
+    // Zero turns pooling off altogether
+    builder = match (pooling.idle_timeout.is_zero(), pooling.max_idle) {
+        (true, _) => builder.pool_max_idle_per_host(0),
+        (false, None) => builder.pool_idle_timeout(pooling.idle_timeout),
+        (false, Some(max)) => builder
+            .pool_idle_timeout(pooling.idle_timeout)
+            .pool_max_idle_per_host(max),
+    };
+
+    if let Some(path) = &settings.ca {
+        let pem = fs::read(path).map_err(|err| format!("Can't read CA {path}: {err}"))?;
+        let cert =
+            reqwest::Certificate::from_pem(&pem).map_err(|err| format!("Bad CA {path}: {err}"))?;
+
+        builder = builder.add_root_certificate(cert);
+    }
+
+    builder.build().map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(labels: &[(&str, &str)]) -> Backend {
+        Backend {
+            name: "/http-web".to_string(),
+            addr: "127.0.0.1:80".to_string(),
+            docker_host: "local".to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn pools_start_over_when_a_backend_goes() {
+        let config = Config::load(&crate::cli::Args::default()).unwrap();
+        let clients = Clients::new(&config);
+        let insecure = backend(&[(TLS_INSECURE_LABEL, "true")]);
+
+        clients.for_backend(&backend(&[]), 0).unwrap();
+        clients.for_backend(&insecure, 0).unwrap();
+        assert_eq!(clients.pools.lock().unwrap().custom.len(), 1);
+
+        // The same generation keeps what it has
+        clients.for_backend(&backend(&[]), 0).unwrap();
+        assert_eq!(clients.pools.lock().unwrap().custom.len(), 1);
+
+        clients.for_backend(&backend(&[]), 1).unwrap();
+        let pools = clients.pools.lock().unwrap();
+        assert_eq!(pools.generation, 1);
+        assert!(pools.custom.is_empty());
+    }
+}
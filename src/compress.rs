@@ -0,0 +1,406 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::{Compression, Encoding};
+use actix_web::web::Bytes;
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use futures_util::stream::{self, Stream, StreamExt};
+use reqwest::header::{
+    HeaderMap, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, VARY,
+};
+use reqwest::StatusCode;
+use std::io::{self, Write};
+
+/// Brotli's window size, as `log2` of the bytes.  The usual default.
+const BROTLI_WINDOW: u32 = 22;
+
+/// The encoding to compress a response with for a client that sent `accept`
+/// as its `Accept-Encoding`, if any: whichever of `offered` it gives the
+/// highest q-value, with ties going to the first.  A client that likes
+/// `identity` better than any of them gets nothing compressed.
+pub fn negotiate(accept: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let prefs = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok())?;
+
+            Some((name, q))
+        })
+        .collect::<Vec<_>>();
+
+    let named = |name: &str| prefs.iter().find(|(n, _)| n == name).map(|&(_, q)| q);
+
+    let identity = named("identity").unwrap_or(0.0);
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for &encoding in offered {
+        let q = named(encoding.name())
+            .or_else(|| named("x-gzip").filter(|_| encoding == Encoding::Gzip))
+            .or_else(|| named("*"))
+            .unwrap_or(0.0);
+
+        if q > 0.0 && q >= identity && best.is_none_or(|(_, best)| q > best) {
+            best = Some((encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a response could be compressed at all, whatever the client takes.
+/// If so, it has to say `Vary: Accept-Encoding` either way, or a cache could
+/// hand a compressed copy to a client that can't read it (or the other way
+/// round).
+pub fn compressible(
+    status: StatusCode,
+    headers: &HeaderMap,
+    length: Option<u64>,
+    compression: &Compression,
+) -> bool {
+    // Ranges are of the body as it is, compressing it would make them wrong
+    if status == StatusCode::PARTIAL_CONTENT || headers.contains_key(CONTENT_RANGE) {
+        return false;
+    }
+
+    if headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+
+    if no_transform || length.is_some_and(|length| length < compression.min_bytes) {
+        return false;
+    }
+
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    compression.types.iter().any(|t| match t.strip_suffix('*') {
+        Some(prefix) => essence.starts_with(prefix),
+        None => essence == *t,
+    })
+}
+
+/// Whether `headers` already has a `Vary` that covers `Accept-Encoding`.
+pub fn varies_by_encoding(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|name| name == "*" || name.eq_ignore_ascii_case("accept-encoding"))
+}
+
+enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, compression: &Compression) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                4096,
+                compression.brotli_level,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                Vec::new(),
+                compression.zstd_level,
+            )?),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(compression.gzip_level),
+            )),
+        })
+    }
+
+    /// Compresses `chunk`, returning what's ready to go out.  With `flush`,
+    /// that's all of it, at some cost to how well it compresses.
+    fn write(&mut self, chunk: &[u8], flush: bool) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Brotli(writer) => {
+                writer.write_all(chunk)?;
+                if flush {
+                    writer.flush()?;
+                }
+                writer.get_mut()
+            }
+            Encoder::Zstd(writer) => {
+                writer.write_all(chunk)?;
+                if flush {
+                    writer.flush()?;
+                }
+                writer.get_mut()
+            }
+            Encoder::Gzip(writer) => {
+                writer.write_all(chunk)?;
+                if flush {
+                    writer.flush()?;
+                }
+                writer.get_mut()
+            }
+        };
+
+        Ok(Bytes::from(std::mem::take(out)))
+    }
+
+    /// Whatever's left once there's nothing more to compress.
+    fn finish(self) -> io::Result<Bytes> {
+        let out = match self {
+            Encoder::Brotli(writer) => writer.into_inner(),
+            Encoder::Zstd(writer) => writer.finish()?,
+            Encoder::Gzip(writer) => writer.finish()?,
+        };
+
+        Ok(Bytes::from(out))
+    }
+}
+
+/// `body` compressed in one go.
+pub fn all(body: &[u8], encoding: Encoding, compression: &Compression) -> io::Result<Bytes> {
+    let mut encoder = Encoder::new(encoding, compression)?;
+    let start = encoder.write(body, false)?;
+    let rest = encoder.finish()?;
+
+    Ok([start, rest].concat().into())
+}
+
+/// `body` compressed as it arrives.  Each chunk is sent on as soon as it's
+/// compressed, so that streamed responses (server-sent events especially)
+/// don't sit in the encoder waiting for more.
+pub fn stream(
+    body: impl Stream<Item = io::Result<Bytes>>,
+    encoding: Encoding,
+    compression: &Compression,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let encoder = Encoder::new(encoding, compression);
+
+    stream::unfold(Some((Box::pin(body), encoder)), |state| async move {
+        let (mut body, encoder) = state?;
+
+        let mut encoder = match encoder {
+            Ok(encoder) => encoder,
+            Err(err) => return Some((Err(err), None)),
+        };
+
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => match encoder.write(&chunk, true) {
+                    // An empty chunk would end a chunked body early
+                    Ok(out) if out.is_empty() => continue,
+                    Ok(out) => return Some((Ok(out), Some((body, Ok(encoder))))),
+                    Err(err) => return Some((Err(err), None)),
+                },
+                Some(Err(err)) => return Some((Err(err), None)),
+                None => return Some((encoder.finish(), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::io::Read;
+
+    fn compression() -> Compression {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip],
+            brotli_level: 4,
+            zstd_level: 3,
+            gzip_level: 6,
+            min_bytes: 1024,
+            types: vec!["text/*".to_string(), "application/json".to_string()],
+        }
+    }
+
+    #[test]
+    fn the_best_liked_encoding_wins() {
+        use Encoding::*;
+        let all = [Brotli, Zstd, Gzip];
+
+        for (accept, offered, picked) in [
+            ("gzip, deflate, br, zstd", &all[..], Some(Brotli)),
+            ("gzip, br", &[Zstd, Gzip, Brotli], Some(Gzip)),
+            ("gzip;q=0.5, br;q=0.9, zstd;q=0.1", &all, Some(Brotli)),
+            ("gzip;q=1.0, br;q=0.9", &all, Some(Gzip)),
+            ("GZIP;Q=0.8, Br;q=0.2", &all, Some(Gzip)),
+            ("x-gzip", &all, Some(Gzip)),
+            ("*", &all, Some(Brotli)),
+            ("*;q=0.5, br;q=0", &all, Some(Zstd)),
+            ("br;q=0, zstd;q=0, gzip;q=0", &all, None),
+            ("*;q=0", &all, None),
+            ("identity", &all, None),
+            ("", &all, None),
+            ("br", &[Gzip], None),
+            ("br;q=nonsense, gzip", &all, Some(Gzip)),
+            ("gzip;q=0.5, identity", &all, None),
+            ("gzip, identity;q=0.5", &all, Some(Gzip)),
+            ("gzip, identity", &all, Some(Gzip)),
+        ] {
+            assert_eq!(negotiate(accept, offered), picked, "{accept:?}");
+        }
+    }
+
+    #[test]
+    fn only_some_responses_are_compressible() {
+        let compression = compression();
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for &(name, value) in pairs {
+                headers.append(name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        let html = [("content-type", "text/html; charset=utf-8")];
+        assert!(compressible(
+            StatusCode::OK,
+            &headers(&html),
+            Some(4096),
+            &compression
+        ));
+        assert!(compressible(
+            StatusCode::OK,
+            &headers(&html),
+            None,
+            &compression
+        ));
+        assert!(compressible(
+            StatusCode::OK,
+            &headers(&[("content-type", "Application/JSON")]),
+            Some(4096),
+            &compression
+        ));
+
+        for (status, pairs, length) in [
+            (StatusCode::OK, &html[..], Some(100)),
+            (StatusCode::OK, &[("content-type", "image/png")], Some(4096)),
+            (StatusCode::OK, &[], Some(4096)),
+            (
+                StatusCode::OK,
+                &[("content-type", "text/html"), ("content-encoding", "gzip")],
+                Some(4096),
+            ),
+            (
+                StatusCode::OK,
+                &[
+                    ("content-type", "text/html"),
+                    ("cache-control", "public, No-Transform"),
+                ],
+                Some(4096),
+            ),
+            (StatusCode::PARTIAL_CONTENT, &html, Some(4096)),
+            (
+                StatusCode::OK,
+                &[
+                    ("content-type", "text/html"),
+                    ("content-range", "bytes 0-9/100"),
+                ],
+                Some(4096),
+            ),
+        ] {
+            let headers = headers(pairs);
+            assert!(
+                !compressible(status, &headers, length, &compression),
+                "{status} {headers:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn vary_is_only_added_when_missing() {
+        for (vary, covered) in [
+            (&[][..], false),
+            (&["Origin"], false),
+            (&["Origin, accept-encoding"], true),
+            (&["Origin", "Accept-Encoding"], true),
+            (&["*"], true),
+        ] {
+            let mut headers = HeaderMap::new();
+            for &value in vary {
+                headers.append(VARY, HeaderValue::from_static(value));
+            }
+            assert_eq!(varies_by_encoding(&headers), covered, "{vary:?}");
+        }
+    }
+
+    fn decompress(encoding: Encoding, compressed: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        match encoding {
+            Encoding::Brotli => {
+                brotli::Decompressor::new(compressed, 4096)
+                    .read_to_end(&mut out)
+                    .unwrap();
+            }
+            Encoding::Zstd => out = zstd::stream::decode_all(compressed).unwrap(),
+            Encoding::Gzip => {
+                flate2::read::GzDecoder::new(compressed)
+                    .read_to_end(&mut out)
+                    .unwrap();
+            }
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn compressed_bodies_come_back_the_same() {
+        let compression = compression();
+        let chunks = ["data: one\n\n", "data: two\n\n", "", "data: three\n\n"];
+        let whole = chunks.concat();
+
+        for encoding in [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip] {
+            let compressed = all(whole.as_bytes(), encoding, &compression).unwrap();
+            assert_eq!(decompress(encoding, &compressed), whole.as_bytes());
+
+            let body = stream::iter(chunks.map(|c| Ok(Bytes::from_static(c.as_bytes()))));
+            let streamed = stream(body, encoding, &compression)
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+
+            // Every chunk is sent on as soon as it's in, and none are empty
+            assert!(streamed.len() >= 3, "{encoding:?}");
+            assert!(streamed.iter().all(|chunk| !chunk.is_empty()));
+            assert_eq!(decompress(encoding, &streamed.concat()), whole.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,92 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use actix_web::HttpRequest;
+use std::any::Any;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often a waiting request checks whether its client is still there.
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Our own handle on a client's socket.  actix only notices a client has gone
+/// once it tries to write the response, which (with a slow backend) can be a
+/// long time after the fact.
+#[derive(Clone)]
+pub struct ClientSocket(Arc<std::net::TcpStream>);
+
+/// For `HttpServer::on_connect`: keeps a handle on every TCP client's socket.
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<TcpStream>() else {
+        return;
+    };
+
+    // A duplicate of the descriptor, so it can't be closed (and reused for
+    // another connection) under us
+    // SAFETY: `stream` owns the descriptor and is alive for the whole call
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+
+    if let Ok(fd) = fd.try_clone_to_owned() {
+        ext.insert(ClientSocket(Arc::new(std::net::TcpStream::from(fd))));
+    }
+}
+
+impl ClientSocket {
+    pub fn of(req: &HttpRequest) -> Option<Self> {
+        req.conn_data::<ClientSocket>().cloned()
+    }
+
+    /// Resolves once the client has closed its side of the connection (which
+    /// a client that only stops sending, without hanging up, looks like too).
+    pub async fn closed(&self) {
+        let mut interval = actix_web::rt::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            // The socket is non-blocking (tokio made it so), so this never
+            // waits.  Anything to read, like a pipelined request, means the
+            // client's still there
+            match self.0.peek(&mut [0]) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// What `future` resolves to, or `None` if the client goes away first.
+pub async fn unless_closed<F: Future>(
+    socket: Option<&ClientSocket>,
+    future: F,
+) -> Option<F::Output> {
+    let Some(socket) = socket else {
+        return Some(future.await);
+    };
+
+    tokio::select! {
+        output = future => Some(output),
+        _ = socket.closed() => None,
+    }
+}
@@ -0,0 +1,336 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Redirect;
+use crate::trace::TraceContext;
+use crate::{CLIENT_CLOSED_STATUS, RETRY_AFTER_SECS, VERSION};
+use actix_web::http::{Method, StatusCode, Version};
+use actix_web::{HttpRequest, HttpResponse};
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// What we know about a request, so that errors shown to the client can be
+/// matched up with the corresponding log line (and so that the request can be
+/// measured once it's done).
+pub struct RequestContext {
+    pub id: String,
+    /// Without the port
+    pub host: String,
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub started: Instant,
+    pub service: Option<String>,
+    /// Whether `service` turned out to exist, as opposed to being whatever
+    /// the host happened to say
+    pub service_known: bool,
+    pub backend: Option<String>,
+    /// Just `name@host:port`, without the Docker host
+    pub backend_addr: Option<String>,
+    /// How long finding a backend took
+    pub discovery_time: Option<Duration>,
+    /// How long the backend took to answer
+    pub upstream_time: Option<Duration>,
+    /// Only when traces are being exported or propagated
+    pub trace: Option<TraceContext>,
+    /// Headers left out for being too big, either way
+    pub dropped_headers: u64,
+}
+
+impl RequestContext {
+    pub fn new(req: &HttpRequest, host: &str) -> Self {
+        // Reuse the client's (or an upstream proxy's) ID when it looks sane,
+        // otherwise make up a short one
+        let id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 64 && v.chars().all(|c| c.is_ascii_graphic()))
+            .map(String::from)
+            .unwrap_or_else(|| format!("{:08x}", rand::random::<u32>()));
+
+        RequestContext {
+            id,
+            host: host.to_string(),
+            method: req.method().clone(),
+            path: req.path().to_string(),
+            query: Some(req.query_string())
+                .filter(|query| !query.is_empty())
+                .map(String::from),
+            version: req.version(),
+            client_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent: header(req, "user-agent"),
+            referer: header(req, "referer"),
+            started: Instant::now(),
+            service: None,
+            service_known: false,
+            backend: None,
+            backend_addr: None,
+            discovery_time: None,
+            upstream_time: None,
+            trace: None,
+            dropped_headers: 0,
+        }
+    }
+
+    pub fn log<T: Debug>(&self, level: &str, reason: T) {
+        log!(
+            "{level}: [{}] {:?} (host={} method={} path={} service={} backend={})",
+            self.id,
+            reason,
+            self.host,
+            self.method,
+            self.path,
+            self.service.as_deref().unwrap_or("-"),
+            self.backend.as_deref().unwrap_or("-"),
+        );
+    }
+
+    pub fn die<T: Debug>(&self, reason: T) -> HttpResponse {
+        self.log("ERROR", reason);
+        HttpResponse::InternalServerError().body(format!(
+            "Internal Server Error [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    pub fn bad_request<T: Debug>(&self, reason: T) -> HttpResponse {
+        self.log("WARN", reason);
+        HttpResponse::BadRequest().body(format!("Bad Request [{}] (dumbrouter/{VERSION})", self.id))
+    }
+
+    /// For clients that didn't send the whole body within
+    /// `DUMBROUTER_CLIENT_BODY_TIMEOUT_SECS`.
+    pub fn request_timeout(&self) -> HttpResponse {
+        self.log("WARN", "Client was too slow sending the body");
+        HttpResponse::RequestTimeout().body(format!(
+            "Request Timeout [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    pub fn payload_too_large(&self, max: u64) -> HttpResponse {
+        self.log("WARN", format!("Request body over {max} bytes"));
+        HttpResponse::PayloadTooLarge().body(format!(
+            "Payload Too Large [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    /// For requests to a service checking JWTs without a good one.
+    pub fn unauthorized(&self, why: &str) -> HttpResponse {
+        self.log("WARN", format!("Rejected token: {why}"));
+        HttpResponse::Unauthorized()
+            .insert_header(("WWW-Authenticate", "Bearer"))
+            .body(format!(
+                "Unauthorized: a valid bearer token is needed.  [{}] (dumbrouter/{VERSION})",
+                self.id
+            ))
+    }
+
+    /// For services that are only a redirect, for a request for `target`.
+    pub fn redirect(&self, redirect: &Redirect, target: &str) -> HttpResponse {
+        let location = redirect.location(target);
+
+        HttpResponse::build(redirect.status)
+            .insert_header(("Location", location.as_str()))
+            .body(format!(
+                "Redirecting to {location}  [{}] (dumbrouter/{VERSION})",
+                self.id
+            ))
+    }
+
+    /// For requests without a `Host` (like HTTP/1.0 ones can be), which
+    /// can't be routed.  Mostly bots, so only the access log hears about it.
+    pub fn missing_host(&self) -> HttpResponse {
+        HttpResponse::BadRequest().body(format!(
+            "A Host header is needed to tell which service this is for.  [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    /// For a `Host` that's empty, too long or not a hostname.  Also not
+    /// logged, for the same reason.
+    pub fn bad_host(&self) -> HttpResponse {
+        HttpResponse::BadRequest().body(format!(
+            "The Host header isn't a valid hostname.  [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    pub fn bad_gateway<T: Debug>(&self, reason: T) -> HttpResponse {
+        self.log("ERROR", reason);
+        HttpResponse::BadGateway().body(format!("Bad Gateway [{}] (dumbrouter/{VERSION})", self.id))
+    }
+
+    pub fn gateway_timeout(&self) -> HttpResponse {
+        self.log("ERROR", "Backend went quiet");
+        HttpResponse::GatewayTimeout().body(format!(
+            "Gateway Timeout [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    pub fn response_too_large(&self, size: u64) -> HttpResponse {
+        self.log(
+            "ERROR",
+            format!("Upstream response too large ({size} bytes)"),
+        );
+        HttpResponse::BadGateway().body(format!(
+            "Upstream response too large [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    /// Nobody's going to see this, it's for the logs and metrics.
+    pub fn client_gone(&self) -> HttpResponse {
+        HttpResponse::build(StatusCode::from_u16(CLIENT_CLOSED_STATUS).unwrap()).finish()
+    }
+
+    /// `page` replaces the default message, with `{service}` and `{id}`
+    /// filled in.  Hosts nothing is for are often someone trying their luck,
+    /// so `hide_names` leaves out what we'd call the service (and which
+    /// dumbrouter this is).
+    pub fn no_backend(
+        &self,
+        status: StatusCode,
+        page: Option<&str>,
+        hide_names: bool,
+    ) -> HttpResponse {
+        let service = match self.service.as_deref() {
+            Some(service) if !hide_names => service,
+            _ => "",
+        };
+        self.log("WARN", "No backend found");
+        let mut builder = HttpResponse::build(status);
+
+        match page {
+            Some(page) => builder
+                .content_type("text/html; charset=utf-8")
+                .body(page.replace("{service}", service).replace("{id}", &self.id)),
+            None if hide_names => builder.body(format!("No backend found.  [{}]", self.id)),
+            None => builder.body(format!(
+                "No backend found for service {service}.  [{}] (dumbrouter/{VERSION})",
+                self.id
+            )),
+        }
+    }
+
+    /// For `DUMBROUTER_NOISE_PATHS` on hosts nothing is for.  Not worth a
+    /// warning, they're just browsers (and bots) asking every host.
+    pub fn noise(&self, status: StatusCode) -> HttpResponse {
+        HttpResponse::build(status).body(format!("Not found.  [{}]", self.id))
+    }
+
+    /// For requests addressed to the router itself.
+    pub fn self_info(&self) -> HttpResponse {
+        HttpResponse::Ok().body(format!(
+            "This is dumbrouter/{VERSION}, not a service.  [{}]",
+            self.id
+        ))
+    }
+
+    /// `page` replaces the default message, with `{service}` and `{id}`
+    /// filled in.
+    pub fn maintenance(&self, page: Option<&str>) -> HttpResponse {
+        let service = self.service.as_deref().unwrap_or("-");
+        let mut builder = HttpResponse::ServiceUnavailable();
+        builder.insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()));
+
+        match page {
+            Some(page) => builder
+                .content_type("text/html; charset=utf-8")
+                .body(page.replace("{service}", service).replace("{id}", &self.id)),
+            None => builder.body(format!(
+                "Service {service} is down for maintenance.  [{}] (dumbrouter/{VERSION})",
+                self.id
+            )),
+        }
+    }
+
+    /// `body` replaces the default message, with `{service}` and `{id}`
+    /// filled in.
+    pub fn circuit_open(&self, status: StatusCode, body: Option<&str>) -> HttpResponse {
+        let service = self.service.as_deref().unwrap_or("-");
+        self.log("WARN", "All backends are ejected");
+        let mut builder = HttpResponse::build(status);
+        builder.insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()));
+
+        match body {
+            Some(body) => builder.body(
+                body.replace("{service}", service)
+                    .replace("{id}", &self.id),
+            ),
+            None => builder.body(format!(
+                "Service {service} is unavailable: all of its backends are failing.  [{}] (dumbrouter/{VERSION})",
+                self.id
+            )),
+        }
+    }
+
+    /// For when containers haven't been listed, because Docker isn't
+    /// answering.
+    pub fn docker_unavailable(&self) -> HttpResponse {
+        self.log("ERROR", "Docker hasn't listed any containers yet");
+        HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+            .body(format!(
+                "Docker isn't responding, so there's nothing to route to yet.  [{}] (dumbrouter/{VERSION})",
+                self.id
+            ))
+    }
+
+    /// For requests that would end up back at the router.
+    pub fn loop_detected(&self, why: &str) -> HttpResponse {
+        self.log("ERROR", format!("Routing loop: {why}"));
+        HttpResponse::build(StatusCode::LOOP_DETECTED).body(format!(
+            "Loop Detected: the backend for this service routes back to dumbrouter.  [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    pub fn ambiguous(&self, services: &[String]) -> HttpResponse {
+        let service = self.service.as_deref().unwrap_or("-");
+        let services = services.join(", ");
+        self.log("ERROR", format!("Ambiguous routing, matches {services}"));
+        HttpResponse::InternalServerError().body(format!(
+            "Ambiguous routing: {service} matches containers of {services}.  [{}] (dumbrouter/{VERSION})",
+            self.id
+        ))
+    }
+
+    pub fn service_down(&self) -> HttpResponse {
+        let service = self.service.as_deref().unwrap_or("-");
+        self.log("WARN", "Backends exist but none are running");
+        HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+            .body(format!(
+                "Service {service} is temporarily down.  [{}] (dumbrouter/{VERSION})",
+                self.id
+            ))
+    }
+}
+
+fn header(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
@@ -0,0 +1,2050 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::{backend_host, Config, DiscoveryMode, DockerEndpoint, IpFamily, Selection};
+use actix_web::http::header::{HeaderMap, COOKIE};
+use actix_web::http::StatusCode;
+use bollard::container::ListContainersOptions;
+use bollard::models::{
+    ContainerSummary, EndpointPortConfigProtocolEnum, Port, PortTypeEnum, Service,
+};
+use bollard::service::ListServicesOptions;
+use bollard::{Docker, API_DEFAULT_VERSION};
+use futures_util::future::join_all;
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::{timeout, MissedTickBehavior};
+
+/// Label on a (placeholder) container that sends its service's traffic to
+/// another service's backends.
+const ALIAS_LABEL: &str = "dumbrouter.alias_of";
+
+/// Label naming the service a container belongs to, in label discovery mode.
+const SERVICE_LABEL: &str = "dumbrouter.service";
+
+/// Label choosing which of a container's ports (by its port inside the
+/// container) to route to.
+const PORT_LABEL: &str = "dumbrouter.port";
+
+/// Label pinning a container to one port in port-aware mode.
+const LISTEN_PORT_LABEL: &str = "dumbrouter.listen_port";
+
+/// Label with the host a container's published ports are reached on, instead
+/// of its daemon's backend host.
+const BACKEND_HOST_LABEL: &str = "dumbrouter.backend_host";
+
+/// Label giving a container a bigger (or smaller) share of its service's
+/// traffic.
+const WEIGHT_LABEL: &str = "dumbrouter.weight";
+
+/// Label rewriting the statuses a container responds with.
+const REMAP_STATUS_LABEL: &str = "dumbrouter.remap_status";
+
+/// Label marking a container as draining.
+const DISABLE_LABEL: &str = "dumbrouter.disable";
+
+/// Label naming the request header that picks a container's variant.
+const VARIANT_HEADER_LABEL: &str = "dumbrouter.variant.header";
+
+/// Label naming the cookie that picks a container's variant.
+const VARIANT_COOKIE_LABEL: &str = "dumbrouter.variant.cookie";
+
+/// Label with the value of `dumbrouter.variant.header` (or `.cookie`) that
+/// requests for a container's variant have.
+const VARIANT_VALUE_LABEL: &str = "dumbrouter.variant.value";
+
+/// Label putting a container into maintenance: it's left running, but isn't
+/// sent requests, and a service with nothing but such containers gets a 503.
+const MAINTENANCE_LABEL: &str = "dumbrouter.maintenance";
+
+/// Label setting a container's state: `active`, `draining` or `disabled`.
+const STATE_LABEL: &str = "dumbrouter.state";
+
+/// Label listing the exact hostnames a container serves, comma-separated.
+const HOSTS_LABEL: &str = "dumbrouter.hosts";
+
+/// Containers aren't listed more often than this, whatever
+/// `DUMBROUTER_CACHE_TTL_MS` says.
+const MIN_REFRESH: Duration = Duration::from_millis(100);
+
+/// How long a daemon that failed to list its containers is left alone for;
+/// doubled for every further failure, up to `MAX_BACKOFF`.
+const BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Timeout bollard applies to its own requests, in seconds.
+const CLIENT_TIMEOUT: u64 = 120;
+
+/// A connected Docker daemon.
+pub struct DockerHost {
+    /// What the daemon is called in logs, i.e. its URL
+    pub name: String,
+    pub docker: Docker,
+    /// Host that the daemon's published ports are reachable on
+    pub backend_host: String,
+    health: Mutex<HostHealth>,
+}
+
+/// How a Docker daemon has been answering lately.
+#[derive(Default)]
+struct HostHealth {
+    /// What it listed the last time it answered, used while it doesn't
+    last: Option<Vec<ContainerSummary>>,
+    /// Listings that failed in a row
+    failures: u32,
+    /// While failing, it isn't asked again before this
+    retry_at: Option<Instant>,
+}
+
+impl DockerHost {
+    pub fn connect(endpoint: &DockerEndpoint, config: &Config) -> Result<Self, String> {
+        let url = endpoint.url.as_str();
+
+        let docker = if url.starts_with("unix://") {
+            Docker::connect_with_unix(url, CLIENT_TIMEOUT, API_DEFAULT_VERSION)
+        } else if let Some(dir) = &endpoint.tls_dir {
+            let dir = Path::new(dir);
+            Docker::connect_with_ssl(
+                url,
+                &dir.join("key.pem"),
+                &dir.join("cert.pem"),
+                &dir.join("ca.pem"),
+                CLIENT_TIMEOUT,
+                API_DEFAULT_VERSION,
+            )
+        } else {
+            Docker::connect_with_http(url, CLIENT_TIMEOUT, API_DEFAULT_VERSION)
+        }
+        .map_err(|err| err.to_string())?;
+
+        let backend_host = endpoint
+            .backend_host
+            .as_deref()
+            .unwrap_or(&config.localhost_ip);
+
+        let backend_host = match config.target_ip_family {
+            Some(family) => pin_family(backend_host, family, &resolve)?,
+            None => backend_host.to_string(),
+        };
+
+        Ok(DockerHost {
+            name: endpoint.url.clone(),
+            docker,
+            backend_host,
+            health: Mutex::default(),
+        })
+    }
+}
+
+/// `host` resolved to an address of `family`, so that every request goes to
+/// the same one rather than whichever the resolver happens to put first.
+/// It's only resolved once: a backend host whose addresses change needs a
+/// restart.
+fn pin_family(
+    host: &str,
+    family: IpFamily,
+    resolve: &dyn Fn(&str) -> std::io::Result<Vec<IpAddr>>,
+) -> Result<String, String> {
+    let (wanted, name) = match family {
+        IpFamily::V4 => (IpAddr::is_ipv4 as fn(&IpAddr) -> bool, "IPv4"),
+        IpFamily::V6 => (IpAddr::is_ipv6 as fn(&IpAddr) -> bool, "IPv6"),
+    };
+
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = resolve(bare)
+        .map_err(|err| format!("Can't resolve {host}: {err}"))?
+        .into_iter()
+        .find(wanted)
+        .ok_or_else(|| format!("{host} has no {name} address"))?;
+
+    if ip.to_string() != bare {
+        log!("INFO: Sending requests for {host} to {ip}");
+    }
+
+    Ok(match ip {
+        IpAddr::V6(ip) => format!("[{ip}]"),
+        ip => ip.to_string(),
+    })
+}
+
+/// Every address `host` has, in the resolver's order.
+fn resolve(host: &str) -> std::io::Result<Vec<IpAddr>> {
+    Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+}
+
+/// A container that a request can be sent to.
+#[derive(Debug)]
+pub struct Backend {
+    /// Container name, including Docker's leading `/`
+    pub name: String,
+    /// `host:port` to send the request to
+    pub addr: String,
+    /// Name of the Docker daemon the container lives on
+    pub docker_host: String,
+    /// The container's labels, for per-backend behavior
+    pub labels: HashMap<String, String>,
+}
+
+impl Backend {
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels.get(name).map(String::as_str)
+    }
+
+    /// From `dumbrouter.weight`, defaulting to 1.
+    fn weight(&self) -> u32 {
+        match self.label(WEIGHT_LABEL).map(|w| w.trim().parse()) {
+            Some(Ok(weight)) => weight,
+            Some(Err(_)) => {
+                log!("WARN: Container {} has a bad weight, using 1", self.name);
+                1
+            }
+            None => 1,
+        }
+    }
+
+    /// `status`, or what `dumbrouter.remap_status` (e.g. `418:200,500:503`)
+    /// says to send instead.
+    pub fn remap_status(&self, status: StatusCode) -> StatusCode {
+        let Some(mappings) = self.label(REMAP_STATUS_LABEL) else {
+            return status;
+        };
+
+        for mapping in mappings.split(',') {
+            let parsed = mapping.split_once(':').and_then(|(from, to)| {
+                let from = from.trim().parse::<u16>().ok()?;
+                let to = StatusCode::from_u16(to.trim().parse().ok()?).ok()?;
+                Some((from, to))
+            });
+
+            match parsed {
+                Some((from, to)) if from == status.as_u16() => return to,
+                Some(_) => {}
+                None => log!(
+                    "WARN: Container {} has a bad status mapping {:?}",
+                    self.name,
+                    mapping
+                ),
+            }
+        }
+
+        status
+    }
+
+    /// From `dumbrouter.state`.  Without one, backends with
+    /// `dumbrouter.disable=true` are draining.  Active ones with a weight of
+    /// 0 are too, as there's no share of the traffic to give them.
+    fn state(&self) -> State {
+        let state = match self.label(STATE_LABEL).map(str::trim) {
+            Some("active") => State::Active,
+            Some("draining") => State::Draining,
+            Some("disabled") => State::Disabled,
+            Some(other) => {
+                log!(
+                    "WARN: Container {} has a bad state {:?}, using active",
+                    self.name,
+                    other
+                );
+                State::Active
+            }
+            None if self.label(DISABLE_LABEL) == Some("true") => State::Draining,
+            None => State::Active,
+        };
+
+        match state {
+            State::Active if self.weight() == 0 => State::Draining,
+            state => state,
+        }
+    }
+
+    /// Draining backends finish up but shouldn't get new traffic.
+    fn is_draining(&self) -> bool {
+        self.state() == State::Draining
+    }
+}
+
+/// Whether a backend gets traffic.
+#[derive(PartialEq)]
+enum State {
+    /// Normally, in proportion to its weight
+    Active,
+    /// Only when nothing else is left (see `select`)
+    Draining,
+    /// Never
+    Disabled,
+}
+
+#[derive(Debug)]
+pub enum DiscoveryError {
+    /// Every Docker daemon failed; this is the last failure
+    Docker(bollard::errors::Error),
+    /// Every Docker daemon failed, the last one by not answering in time
+    Timeout(String),
+    /// Every Docker daemon failed, the last one recently enough that it's
+    /// being left alone for now
+    BackingOff(String),
+    /// Containers haven't been listed successfully yet
+    NotListed,
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiscoveryError::Docker(err) => write!(f, "Docker error: {err}"),
+            DiscoveryError::Timeout(host) => write!(f, "Docker host {host} timed out"),
+            DiscoveryError::BackingOff(host) => {
+                write!(f, "Docker host {host} isn't answering, will retry later")
+            }
+            DiscoveryError::NotListed => write!(f, "Containers haven't been listed yet"),
+        }
+    }
+}
+
+/// A container and the Docker daemon it came from.
+struct Listed {
+    /// Index into `Discovery::hosts`
+    host: usize,
+    container: ContainerSummary,
+}
+
+/// The Docker daemons, plus a snapshot of what's on them that's refreshed in
+/// the background, so that requests never wait on Docker.
+pub struct Discovery {
+    pub hosts: Vec<DockerHost>,
+    refresh_every: Duration,
+    /// List Swarm services instead of containers
+    swarm: bool,
+    mode: DiscoveryMode,
+    /// How long each daemon gets to list its containers before we carry on
+    /// without it
+    docker_timeout: Duration,
+    /// The last listing, and when it was taken
+    snapshot: Mutex<Option<(Instant, Arc<Vec<Listed>>)>>,
+    /// Every (host, container, address) seen in the last listing
+    endpoints: Mutex<HashSet<(usize, String, String)>>,
+    /// Bumped whenever one of `endpoints` goes away
+    generation: AtomicU64,
+    /// Whether containers have been listed successfully yet
+    ready: AtomicBool,
+    /// How long a backend that refused a connection is left out for
+    eject_for: Option<Duration>,
+    /// Backends left out until then, by Docker host and container name
+    ejected: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl Discovery {
+    pub fn new(hosts: Vec<DockerHost>, config: &Config) -> Self {
+        Discovery {
+            hosts,
+            refresh_every: config.cache_ttl.max(MIN_REFRESH),
+            swarm: config.swarm,
+            mode: config.discovery,
+            docker_timeout: config.docker_timeout,
+            snapshot: Mutex::new(None),
+            endpoints: Mutex::new(HashSet::new()),
+            generation: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+            eject_for: config.eject_for,
+            ejected: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Leaves `backend` out of lookups for `DUMBROUTER_EJECT_MS`, if set.
+    pub fn eject(&self, backend: &Backend) {
+        if let Some(eject_for) = self.eject_for {
+            log!(
+                "WARN: Ejecting {} on {} for {}ms",
+                backend.name,
+                backend.docker_host,
+                eject_for.as_millis()
+            );
+
+            let key = (backend.docker_host.clone(), backend.name.clone());
+            self.ejected
+                .lock()
+                .unwrap()
+                .insert(key, Instant::now() + eject_for);
+        }
+    }
+
+    fn is_ejected(&self, backend: &Backend) -> bool {
+        let mut ejected = self.ejected.lock().unwrap();
+        let now = Instant::now();
+        ejected.retain(|_, until| *until > now);
+        ejected.contains_key(&(backend.docker_host.clone(), backend.name.clone()))
+    }
+
+    /// False until containers have been listed once.
+    pub fn ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Changes whenever a backend that used to be listed disappears (or is
+    /// replaced), so that connections pooled to it can be thrown away.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// How long ago the current snapshot was taken, if there is one.
+    pub fn snapshot_age(&self) -> Option<Duration> {
+        let snapshot = self.snapshot.lock().unwrap();
+        snapshot.as_ref().map(|(at, _)| at.elapsed())
+    }
+
+    /// Every container on every daemon, as of the last refresh.
+    fn containers(&self) -> Result<Arc<Vec<Listed>>, DiscoveryError> {
+        match &*self.snapshot.lock().unwrap() {
+            Some((_, containers)) => Ok(containers.clone()),
+            None => Err(DiscoveryError::NotListed),
+        }
+    }
+
+    /// Refreshes the snapshot every `DUMBROUTER_CACHE_TTL_MS`, forever.
+    pub async fn refresh_forever(&self) {
+        let start = tokio::time::Instant::now() + self.refresh_every;
+        let mut ticks = tokio::time::interval_at(start, self.refresh_every);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            ticks.tick().await;
+            // Failures are logged per daemon, and the old snapshot stays
+            let _ = self.refresh().await;
+        }
+    }
+
+    /// Lists every container and replaces the snapshot with them.  Returns
+    /// how many there are.
+    pub async fn refresh(&self) -> Result<usize, DiscoveryError> {
+        let containers =
+            Arc::new(list_all(&self.hosts, self.swarm, self.mode, self.docker_timeout).await?);
+        *self.snapshot.lock().unwrap() = Some((Instant::now(), containers.clone()));
+
+        let endpoints = containers
+            .iter()
+            .filter_map(|listed| {
+                let id = listed.container.id.clone().unwrap_or_default();
+                Some((listed.host, id, address(listed, &self.hosts).ok()?))
+            })
+            .collect::<HashSet<_>>();
+
+        let mut seen = self.endpoints.lock().unwrap();
+        if !seen.is_subset(&endpoints) {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+        *seen = endpoints;
+        self.ready.store(true, Ordering::Relaxed);
+
+        Ok(containers.len())
+    }
+}
+
+/// Result of looking for a backend for a service.
+pub enum Lookup {
+    /// A container to send the request to
+    Found(Backend),
+    /// Containers for the service exist, but none of them are running (or
+    /// healthy)
+    Down,
+    /// Nothing matches the service at all
+    Missing,
+    /// Following `dumbrouter.alias_of` labels led back to a service we'd
+    /// already visited
+    AliasCycle(Vec<String>),
+    /// Containers of more than one service matched, in strict mode
+    Ambiguous(Vec<String>),
+    /// The service's backends are running, but have all been ejected for
+    /// failing
+    CircuitOpen,
+    /// The service's containers are in maintenance (and the others, if any,
+    /// can't be used either)
+    Maintenance,
+}
+
+/// How a lookup went, step by step, for `/_dumbrouter/resolve`.
+#[derive(Default)]
+pub struct Explanation {
+    /// The container whose `dumbrouter.hosts` label claimed the host
+    pub claimed_by: Option<String>,
+    /// Every service visited, following `dumbrouter.alias_of` labels
+    pub services: Vec<String>,
+    /// Containers belonging to the service, and why they were skipped (if
+    /// they were)
+    pub candidates: Vec<(String, Option<String>)>,
+    /// What the backend was picked from
+    pub eligible: Vec<Eligible>,
+    /// Whether the request was for a variant (`dumbrouter.variant.*`)
+    pub variant: bool,
+}
+
+pub struct Eligible {
+    pub name: String,
+    pub addr: String,
+    pub docker_host: String,
+    pub weight: u32,
+    pub draining: bool,
+}
+
+/// Finds a backend for a request to `host` with `headers` from `client`,
+/// which `service` was derived from.  Containers claiming `host` in their
+/// `dumbrouter.hosts` label win over the usual name-based matching.
+pub fn dest_host_for_service(
+    discovery: &Discovery,
+    config: &Config,
+    host: &str,
+    headers: &HeaderMap,
+    client: Option<IpAddr>,
+    service: &str,
+    mut explain: Option<&mut Explanation>,
+) -> Result<Lookup, DiscoveryError> {
+    let containers = discovery.containers()?;
+
+    if let Some(claimed) = claiming_host(&containers, host) {
+        if let Some(explain) = explain.as_deref_mut() {
+            explain.claimed_by = name_of(&claimed.container).map(String::from);
+        }
+
+        return Ok(pick_backend(
+            std::iter::once(claimed),
+            client,
+            discovery,
+            config,
+            explain,
+        ));
+    }
+
+    let mut service = service.to_string();
+    let mut seen = vec![];
+
+    while let Some(target) = alias_for(&containers, &service, config.discovery) {
+        seen.push(service);
+
+        if seen.contains(&target) {
+            seen.push(target);
+            return Ok(Lookup::AliasCycle(seen));
+        }
+
+        service = target;
+    }
+
+    if let Some(explain) = explain.as_deref_mut() {
+        explain.services = seen.clone();
+        explain.services.push(service.clone());
+    }
+
+    let mut candidates = containers
+        .iter()
+        .filter(|l| belongs_to(&l.container, &service, config.discovery))
+        .collect::<Vec<_>>();
+
+    // Containers pinned to the request's port win over ones taking any port
+    if service.contains('@')
+        && candidates
+            .iter()
+            .any(|l| listen_port(&l.container).is_some())
+    {
+        if let Some(explain) = explain.as_deref_mut() {
+            for l in candidates
+                .iter()
+                .filter(|l| listen_port(&l.container).is_none())
+            {
+                let name = name_of(&l.container).unwrap_or("?").to_string();
+                let why = "Other containers are pinned to the port".to_string();
+                explain.candidates.push((name, Some(why)));
+            }
+        }
+
+        candidates.retain(|l| listen_port(&l.container).is_some());
+    }
+
+    if config.strict_match {
+        let mut services = services_of(&candidates);
+        services.sort();
+        services.dedup();
+
+        if services.len() > 1 {
+            return Ok(Lookup::Ambiguous(services));
+        }
+    }
+
+    // Requests for a variant only get its containers, and other requests
+    // never do
+    let variant = candidates
+        .iter()
+        .any(|l| is_variant_for(&l.container, headers) == Some(true));
+
+    if let Some(explain) = explain.as_deref_mut() {
+        explain.variant = variant;
+
+        for l in &candidates {
+            let why = match is_variant_for(&l.container, headers) {
+                Some(false) if variant => "Another variant",
+                Some(false) => "Only for requests for its variant",
+                None if variant => "Not the request's variant",
+                _ => continue,
+            };
+
+            let name = name_of(&l.container).unwrap_or("?").to_string();
+            explain.candidates.push((name, Some(why.to_string())));
+        }
+    }
+
+    candidates.retain(|l| is_variant_for(&l.container, headers) == variant.then_some(true));
+
+    Ok(pick_backend(
+        candidates.into_iter(),
+        client,
+        discovery,
+        config,
+        explain,
+    ))
+}
+
+/// The service each of `containers` is meant for, as far as we can tell: see
+/// `own_service`.  A replica number (`http-foo-2`) only counts as one when
+/// there's a container without it (`http-foo`) among them, as otherwise it
+/// could just as well be a service called `foo-2`.
+fn services_of(containers: &[&Listed]) -> Vec<String> {
+    let services = containers
+        .iter()
+        .filter_map(|l| own_service(&l.container))
+        .collect::<Vec<_>>();
+
+    services
+        .iter()
+        .map(|service| match replica_of(service) {
+            Some(base) if services.iter().any(|s| s == base) => base.to_string(),
+            _ => service.clone(),
+        })
+        .collect()
+}
+
+/// `service` without a replica number (`foo` for `foo-2`), if it has one.
+fn replica_of(service: &str) -> Option<&str> {
+    let (base, number) = service.rsplit_once('-')?;
+
+    (!base.is_empty() && !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+        .then_some(base)
+}
+
+/// The service a container says it's meant for: its `dumbrouter.service`
+/// label, or its name without the `http-`/`http-prod-` prefix.
+fn own_service(c: &ContainerSummary) -> Option<String> {
+    if let Some(service) = c.labels.as_ref().and_then(|l| l.get(SERVICE_LABEL)) {
+        return Some(service.clone());
+    }
+
+    let name = name_of(c)?;
+    let bare = name.strip_prefix('/').unwrap_or(name);
+    let service = bare
+        .strip_prefix("http-prod-")
+        .or_else(|| bare.strip_prefix("http-"))?;
+
+    Some(service.to_string())
+}
+
+/// Lists containers (or Swarm services, with `swarm`) on every Docker daemon
+/// at once, skipping daemons that fail or are too slow as long as at least one
+/// of them answers.
+async fn list_all(
+    hosts: &[DockerHost],
+    swarm: bool,
+    mode: DiscoveryMode,
+    limit: Duration,
+) -> Result<Vec<Listed>, DiscoveryError> {
+    let results = join_all(
+        hosts
+            .iter()
+            .enumerate()
+            .map(|(i, host)| async move { Ok((i, list(host, swarm, mode, limit).await?)) }),
+    )
+    .await;
+
+    let mut listed = vec![];
+    let mut last_err = None;
+    let mut any_ok = false;
+
+    for result in results {
+        match result {
+            Ok((host, containers)) => {
+                any_ok = true;
+                listed.extend(
+                    containers
+                        .into_iter()
+                        .map(|container| Listed { host, container }),
+                );
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    match last_err {
+        Some(err) if !any_ok => Err(err),
+        _ => Ok(listed),
+    }
+}
+
+/// Lists what's on one Docker daemon.  A daemon that fails or hangs is left
+/// alone for a while and its last listing is used instead, so that it only
+/// holds up the occasional request rather than every one.
+async fn list(
+    host: &DockerHost,
+    swarm: bool,
+    mode: DiscoveryMode,
+    limit: Duration,
+) -> Result<Vec<ContainerSummary>, DiscoveryError> {
+    let backing_off = {
+        let health = host.health.lock().unwrap();
+        health.retry_at.is_some_and(|at| Instant::now() < at)
+    };
+
+    let result = if backing_off {
+        Err(DiscoveryError::BackingOff(host.name.clone()))
+    } else {
+        let listing = async {
+            if swarm {
+                let services = host
+                    .docker
+                    .list_services(None::<ListServicesOptions<String>>)
+                    .await?;
+                return Ok(services.into_iter().map(service_as_container).collect());
+            }
+
+            // Stopped containers are listed too so that we can tell "down"
+            // apart from "doesn't exist" (and so that aliases work from
+            // stopped placeholders)
+            let listings = join_all(listing_filters(mode).into_iter().map(|(key, value)| {
+                let options = ListContainersOptions {
+                    all: true,
+                    filters: HashMap::from([(key, vec![value])]),
+                    ..Default::default()
+                };
+
+                host.docker.list_containers(Some(options))
+            }))
+            .await;
+
+            // A container can turn up in more than one listing
+            let mut seen = HashSet::new();
+            let mut containers = vec![];
+            for listing in listings {
+                for c in listing? {
+                    if seen.insert(c.id.clone()) {
+                        containers.push(c);
+                    }
+                }
+            }
+
+            Ok(containers)
+        };
+
+        match timeout(limit, listing).await {
+            Ok(Ok(containers)) => Ok(containers),
+            Ok(Err(err)) => Err(DiscoveryError::Docker(err)),
+            Err(_) => Err(DiscoveryError::Timeout(host.name.clone())),
+        }
+    };
+
+    let mut health = host.health.lock().unwrap();
+
+    match result {
+        Ok(containers) => {
+            if health.failures > 0 {
+                log!("INFO: Docker host {} is answering again", host.name);
+            }
+
+            health.failures = 0;
+            health.retry_at = None;
+            health.last = Some(containers.clone());
+            Ok(containers)
+        }
+        Err(err) => {
+            if !backing_off {
+                let doublings = health.failures.min(6);
+                let delay = (BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF);
+                health.failures += 1;
+                health.retry_at = Some(Instant::now() + delay);
+
+                let fallback = match health.last {
+                    Some(_) => "using its last listing",
+                    None => "with nothing to fall back on",
+                };
+                log!(
+                    "WARN: Couldn't list containers: {err} ({fallback}, next try in {}ms)",
+                    delay.as_millis()
+                );
+            }
+
+            health.last.clone().ok_or(err)
+        }
+    }
+}
+
+/// Docker filters that, between them, match every container that could be
+/// routed to, so that the daemon doesn't send the ones that can't.  Docker
+/// ANDs different filters together, so each gets a listing of its own.  The
+/// name filter is a regex matched anywhere in the name (which Docker starts
+/// with a `/` and Podman doesn't), so it's anchored; `belongs_to` still does
+/// the precise check.
+fn listing_filters(mode: DiscoveryMode) -> Vec<(&'static str, &'static str)> {
+    let mut filters = vec![("label", HOSTS_LABEL)];
+
+    if mode != DiscoveryMode::Label {
+        filters.push(("name", "^/?http-"));
+    }
+
+    if mode != DiscoveryMode::Name {
+        filters.push(("label", SERVICE_LABEL));
+    }
+
+    filters
+}
+
+/// The container that lists `host` in its `dumbrouter.hosts` label.  If more
+/// than one does, the one with the lowest name wins.
+fn claiming_host<'a>(containers: &'a [Listed], host: &str) -> Option<&'a Listed> {
+    let mut claims = containers
+        .iter()
+        .filter(|l| {
+            let hosts = l.container.labels.as_ref().and_then(|l| l.get(HOSTS_LABEL));
+            hosts.is_some_and(|hosts| {
+                hosts
+                    .split(',')
+                    .any(|h| h.trim().eq_ignore_ascii_case(host))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    claims.sort_by_key(|l| name_of(&l.container));
+
+    if claims.len() > 1 {
+        log!(
+            "WARN: {} is claimed by {} containers, using {}",
+            host,
+            claims.len(),
+            name_of(&claims[0].container).unwrap_or("?")
+        );
+    }
+
+    claims.first().copied()
+}
+
+/// Makes a Swarm service look like a container, so it's routed like one.
+/// Tasks come and go under names like `http-foo.1.<id>`, but the service
+/// keeps its name, labels and published ports, and the routing mesh forwards
+/// those to whichever task is running.
+fn service_as_container(service: Service) -> ContainerSummary {
+    let spec = service.spec.unwrap_or_default();
+
+    // Scaled down to nothing is as good as stopped
+    let replicas = spec
+        .mode
+        .as_ref()
+        .and_then(|mode| mode.replicated.as_ref())
+        .and_then(|replicated| replicated.replicas);
+    let state = if replicas == Some(0) {
+        "exited"
+    } else {
+        "running"
+    };
+
+    let ports = service
+        .endpoint
+        .and_then(|endpoint| endpoint.ports)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|port| {
+            matches!(
+                port.protocol,
+                None | Some(
+                    EndpointPortConfigProtocolEnum::TCP | EndpointPortConfigProtocolEnum::EMPTY
+                )
+            )
+        })
+        .map(|port| Port {
+            ip: None,
+            private_port: port.target_port.unwrap_or_default(),
+            public_port: port.published_port,
+            typ: Some(PortTypeEnum::TCP),
+        })
+        .collect();
+
+    ContainerSummary {
+        id: service.id,
+        names: spec.name.map(|name| vec![format!("/{name}")]),
+        labels: spec.labels,
+        ports: Some(ports),
+        state: Some(state.to_string()),
+        ..Default::default()
+    }
+}
+
+/// The container's name, if it has exactly one.
+fn name_of(c: &ContainerSummary) -> Option<&str> {
+    match c.names.as_deref() {
+        Some([name]) => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether a request with `headers` is for `c`'s variant, if `c` is one at
+/// all: its `dumbrouter.variant.header` or `.cookie` has to have the value
+/// in `dumbrouter.variant.value`.
+fn is_variant_for(c: &ContainerSummary, headers: &HeaderMap) -> Option<bool> {
+    let labels = c.labels.as_ref()?;
+    let value = labels.get(VARIANT_VALUE_LABEL)?;
+    let header = labels.get(VARIANT_HEADER_LABEL);
+    let cookie = labels.get(VARIANT_COOKIE_LABEL);
+
+    if header.is_none() && cookie.is_none() {
+        return None;
+    }
+
+    let by_header = header.is_some_and(|name| {
+        headers
+            .get_all(name.as_str())
+            .any(|v| v.to_str().is_ok_and(|v| v.trim() == value))
+    });
+
+    let by_cookie = cookie.is_some_and(|name| {
+        headers
+            .get_all(COOKIE)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.split_once('='))
+            .any(|(k, v)| k.trim() == name && v.trim() == value)
+    });
+
+    Some(by_header || by_cookie)
+}
+
+fn listen_port(c: &ContainerSummary) -> Option<&str> {
+    c.labels
+        .as_ref()?
+        .get(LISTEN_PORT_LABEL)
+        .map(String::as_str)
+}
+
+/// Whether the container belongs to `service`: by its name (`http-service*`
+/// or `http-prod-service*`) and/or its `dumbrouter.service` label, depending
+/// on the discovery mode.  A `service@port` (from port-aware mode) only
+/// matches containers without a `dumbrouter.listen_port` label or with one
+/// saying `port`.
+fn belongs_to(c: &ContainerSummary, service: &str, mode: DiscoveryMode) -> bool {
+    let Some(name) = name_of(c) else {
+        return false;
+    };
+
+    let service = match service.split_once('@') {
+        Some((service, port)) => {
+            if listen_port(c).is_some_and(|p| p != port) {
+                return false;
+            }
+
+            service
+        }
+        None => service,
+    };
+
+    let by_label = || {
+        c.labels
+            .as_ref()
+            .and_then(|l| l.get(SERVICE_LABEL))
+            .is_some_and(|s| s == service)
+    };
+
+    let by_name = || {
+        // Docker always puts a `/` in front of names, Podman's compatible API
+        // doesn't always
+        let bare = name.strip_prefix('/').unwrap_or(name);
+
+        let start_base = format!("http-{}", service);
+        let start_prod = format!("http-prod-{}", service);
+
+        bare.starts_with(&start_base) || bare.starts_with(&start_prod)
+    };
+
+    match mode {
+        DiscoveryMode::Name => by_name(),
+        DiscoveryMode::Label => by_label(),
+        DiscoveryMode::Both => by_label() || by_name(),
+    }
+}
+
+/// The service that `service` is an alias of, if any of its containers say so.
+fn alias_for(containers: &[Listed], service: &str, mode: DiscoveryMode) -> Option<String> {
+    containers
+        .iter()
+        .map(|l| &l.container)
+        .filter(|c| belongs_to(c, service, mode))
+        .find_map(|c| c.labels.as_ref()?.get(ALIAS_LABEL))
+        .filter(|target| !target.is_empty())
+        .cloned()
+}
+
+/// Why a container can't take a request.
+enum Skip {
+    /// It's stopped or not healthy, so the service is down (for now)
+    Down(&'static str),
+    /// There's something wrong with how it's set up
+    Unusable(String),
+}
+
+/// The backend that a request could be sent to for `listed`.
+fn as_backend(listed: &Listed, hosts: &[DockerHost], config: &Config) -> Result<Backend, Skip> {
+    let c = &listed.container;
+    let name = name_of(c).unwrap_or("?");
+
+    let state = c.state.as_deref().unwrap_or_default();
+    if !config
+        .eligible_states
+        .iter()
+        .any(|eligible| eligible == state)
+    {
+        return Err(Skip::Down(match state {
+            "running" => "Running, but not in DUMBROUTER_ELIGIBLE_STATES",
+            _ => "Not running",
+        }));
+    }
+
+    // Containers without a HEALTHCHECK don't mention health in their status
+    // at all, so they're always let through
+    if config.check_health {
+        let status = c.status.as_deref().unwrap_or("");
+        if status.contains("(health: starting)") {
+            return Err(Skip::Down("Still starting"));
+        }
+        if status.contains("(unhealthy)") {
+            return Err(Skip::Down("Unhealthy"));
+        }
+    }
+
+    let labels = c.labels.clone().unwrap_or_default();
+    if let Some(Err(err)) = labels
+        .get(BACKEND_HOST_LABEL)
+        .map(|labeled| backend_host(BACKEND_HOST_LABEL, labeled))
+    {
+        log!("WARN: Container {name} has a bad backend host, ignoring it: {err}");
+    }
+
+    Ok(Backend {
+        name: name.to_string(),
+        addr: address(listed, hosts)?,
+        docker_host: hosts[listed.host].name.clone(),
+        labels,
+    })
+}
+
+/// The `host:port` that `listed` is reached at, whatever state it's in.
+fn address(Listed { host, container: c }: &Listed, hosts: &[DockerHost]) -> Result<String, Skip> {
+    let host = &hosts[*host];
+    let name = name_of(c).unwrap_or("?");
+    let no_port = || Skip::Unusable(format!("Container {name} is http, but has no port!"));
+
+    let ports = c.ports.as_ref().ok_or_else(no_port)?;
+    if ports.is_empty() {
+        return Err(no_port());
+    }
+
+    // Exposed but unpublished ports have no public port.  Podman leaves out
+    // the IP of ports published on every interface, so that can't be relied
+    // on
+    let ports = ports
+        .iter()
+        .filter(|p| p.public_port.is_some())
+        .collect::<Vec<_>>();
+
+    if ports.is_empty() {
+        return Err(Skip::Unusable(format!(
+            "Container {name} needs 1 eligible port, but has 0!"
+        )));
+    }
+
+    let private_port = match c.labels.as_ref().and_then(|l| l.get(PORT_LABEL)) {
+        Some(wanted) => {
+            ports
+                .iter()
+                .find(|p| p.private_port.to_string() == wanted.trim())
+                .ok_or_else(|| {
+                    Skip::Unusable(format!(
+                        "Container {name} wants port {wanted}, but doesn't publish it!"
+                    ))
+                })?
+                .private_port
+        }
+        None => ports.first().unwrap().private_port,
+    };
+
+    // Ports published on every interface say `0.0.0.0` (or `::`), and those
+    // are reached through the daemon's backend host.  One published on a
+    // specific IP is only reachable there
+    let specific_ip = |p: &Port| {
+        p.ip.as_deref()
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .filter(|ip| !ip.is_unspecified())
+    };
+
+    // A port can be published more than once (`-p 127.0.0.1:8081:80 -p
+    // 8082:80`).  The one on every interface wins, as the backend host is
+    // what's been set up to reach containers from here
+    let bindings = ports
+        .iter()
+        .filter(|p| p.private_port == private_port)
+        .collect::<Vec<_>>();
+    let port = bindings
+        .iter()
+        .find(|p| specific_ip(p).is_none())
+        .unwrap_or(&bindings[0]);
+
+    let bound_ip = specific_ip(port);
+
+    let labeled = c
+        .labels
+        .as_ref()
+        .and_then(|l| l.get(BACKEND_HOST_LABEL))
+        .and_then(|labeled| backend_host(BACKEND_HOST_LABEL, labeled).ok());
+
+    let backend_host = match (labeled, bound_ip) {
+        (Some(labeled), _) => labeled,
+        (None, Some(IpAddr::V6(ip))) => format!("[{ip}]"),
+        (None, Some(ip)) => ip.to_string(),
+        (None, None) => host.backend_host.clone(),
+    };
+
+    Ok(format!(
+        "{}:{}",
+        backend_host,
+        port.public_port.as_ref().unwrap()
+    ))
+}
+
+fn pick_backend<'a>(
+    candidates: impl Iterator<Item = &'a Listed>,
+    client: Option<IpAddr>,
+    discovery: &Discovery,
+    config: &Config,
+    mut explain: Option<&mut Explanation>,
+) -> Lookup {
+    let mut saw_down = false;
+    let mut saw_ejected = false;
+    let mut saw_maintenance = false;
+    let mut backends = vec![];
+
+    for listed in candidates {
+        let Some(name) = name_of(&listed.container) else {
+            continue;
+        };
+
+        let skipped = match as_backend(listed, &discovery.hosts, config) {
+            Ok(backend) if backend.state() == State::Disabled => {
+                saw_down = true;
+                Some("Disabled by its dumbrouter.state label".to_string())
+            }
+            Ok(backend) if backend.label(MAINTENANCE_LABEL) == Some("true") => {
+                saw_maintenance = true;
+                Some("In maintenance".to_string())
+            }
+            Ok(backend) if discovery.is_ejected(&backend) => {
+                saw_ejected = true;
+                Some("Ejected for failing".to_string())
+            }
+            Ok(backend) => {
+                backends.push(backend);
+                None
+            }
+            Err(Skip::Down(why)) => {
+                saw_down = true;
+                Some(why.to_string())
+            }
+            Err(Skip::Unusable(why)) => {
+                log!("WARN: {why}");
+                Some(why)
+            }
+        };
+
+        if let Some(explain) = explain.as_deref_mut() {
+            explain.candidates.push((name.to_string(), skipped));
+        }
+    }
+
+    if let Some(explain) = explain {
+        explain.eligible = backends
+            .iter()
+            .map(|b| Eligible {
+                name: b.name.clone(),
+                addr: b.addr.clone(),
+                docker_host: b.docker_host.clone(),
+                weight: b.weight(),
+                draining: b.is_draining(),
+            })
+            .collect();
+    }
+
+    let usable = !backends.is_empty();
+
+    match select(backends, client, config, &mut rand::thread_rng()) {
+        Some(backend) => Lookup::Found(backend),
+        None if saw_maintenance => Lookup::Maintenance,
+        None if saw_ejected => Lookup::CircuitOpen,
+        None if saw_down || usable => Lookup::Down,
+        None => Lookup::Missing,
+    }
+}
+
+/// Picks one of the backends in proportion to their weights: at random, or
+/// by `client` with `DUMBROUTER_SELECTION=hash`.  Draining backends are only
+/// used when nothing else is left, and then only if
+/// `DUMBROUTER_SERVE_DRAINING_LAST_RESORT` is set.  Random picks come from
+/// `rng`.
+fn select(
+    backends: Vec<Backend>,
+    client: Option<IpAddr>,
+    config: &Config,
+    rng: &mut impl Rng,
+) -> Option<Backend> {
+    let (mut draining, mut active): (Vec<_>, Vec<_>) =
+        backends.into_iter().partition(Backend::is_draining);
+
+    // Most services have one container, and there's nothing to choose then
+    if active.len() == 1 {
+        return active.pop();
+    }
+
+    let hash_by = client.filter(|_| config.selection == Selection::Hash);
+
+    if let (Some(client), false) = (hash_by, active.is_empty()) {
+        return by_hash(active, client, true);
+    }
+
+    if !active.is_empty() {
+        let indices = (0..active.len()).collect::<Vec<_>>();
+        let i = *indices.choose_weighted(rng, |&i| active[i].weight()).ok()?;
+
+        return active.into_iter().nth(i);
+    }
+
+    if !config.serve_draining_last_resort {
+        return None;
+    }
+
+    // Weights don't mean much once everything is draining
+    match (draining.len(), hash_by) {
+        (0 | 1, _) => draining.pop(),
+        (_, Some(client)) => by_hash(draining, client, false),
+        _ => draining.into_iter().choose(rng),
+    }
+}
+
+/// Rendezvous hashing: every backend gets a score from hashing it together
+/// with `client`, and the highest score wins.  A client only moves when its
+/// backend goes away or one that outscores it turns up, so adding or removing
+/// a backend moves about as few clients as it can.  With `weighted`, weights
+/// scale the scores so that each backend still gets its share.
+fn by_hash(backends: Vec<Backend>, client: IpAddr, weighted: bool) -> Option<Backend> {
+    backends
+        .into_iter()
+        .map(|backend| {
+            let hash = stable_hash(format!("{client} {}", backend.name).as_bytes());
+            // Evenly spread over (0, 1)
+            let u = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            let weight = if weighted {
+                backend.weight() as f64
+            } else {
+                1.0
+            };
+
+            (weight / -u.ln(), backend)
+        })
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, backend)| backend)
+}
+
+/// FNV-1a, finished off with splitmix64's mixing so that similar inputs get
+/// very different hashes.  Unlike std's hasher it's the same in every build,
+/// so dumbrouters in front of the same containers agree.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    /// The names of the containers a lookup for `service` looked at.
+    fn candidates(discovery: &Discovery, config: &Config, service: &str) -> Vec<String> {
+        let mut explain = Explanation::default();
+        let headers = HeaderMap::new();
+        dest_host_for_service(
+            discovery,
+            config,
+            "",
+            &headers,
+            None,
+            service,
+            Some(&mut explain),
+        )
+        .unwrap();
+
+        explain
+            .candidates
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// The backend a lookup for `service` picked.
+    fn found(discovery: &Discovery, config: &Config, service: &str) -> Backend {
+        let headers = HeaderMap::new();
+        match dest_host_for_service(discovery, config, "", &headers, None, service, None) {
+            Ok(Lookup::Found(backend)) => backend,
+            _ => panic!("no backend for {service}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn listing_is_scoped_to_routable_names() {
+        let (url, requests) = testing::docker(json!([])).await;
+        let config = testing::config(&url);
+        testing::discovery(&config).await;
+
+        let mut filters = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.target.contains("/containers/json"))
+            .map(|r| {
+                let url = reqwest::Url::parse(&format!("http://docker{}", r.target)).unwrap();
+                let (_, filters) = url.query_pairs().find(|(k, _)| k == "filters").unwrap();
+                serde_json::from_str::<serde_json::Value>(&filters).unwrap()
+            })
+            .collect::<Vec<_>>();
+        filters.sort_by_key(|f| f.to_string());
+
+        assert_eq!(
+            filters,
+            [
+                json!({ "label": [HOSTS_LABEL] }),
+                json!({ "name": ["^/?http-"] }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_prefix_matches_are_candidates() {
+        // As a substring filter would let through
+        let (url, _) = testing::docker(json!([
+            testing::container("http-foo", 1, json!({})),
+            testing::container("http-foo-2", 2, json!({})),
+            testing::container("http-prod-foo", 3, json!({})),
+            testing::container("my-http-foo", 4, json!({})),
+            testing::container("http-bar", 5, json!({})),
+        ]))
+        .await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        let mut found = candidates(&discovery, &config, "foo");
+        found.sort();
+        assert_eq!(found, ["/http-foo", "/http-foo-2", "/http-prod-foo"]);
+    }
+
+    #[tokio::test]
+    async fn every_eligible_container_is_picked_from() {
+        let containers = (0..20)
+            .map(|i| testing::container(&format!("http-foo-{i}"), 1000 + i, json!({})))
+            .collect::<Vec<_>>();
+        let (url, _) = testing::docker(json!(containers)).await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        let mut explain = Explanation::default();
+        let headers = HeaderMap::new();
+        let lookup = dest_host_for_service(
+            &discovery,
+            &config,
+            "",
+            &headers,
+            None,
+            "foo",
+            Some(&mut explain),
+        );
+
+        assert!(matches!(lookup, Ok(Lookup::Found(_))));
+        assert_eq!(explain.eligible.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn ports_are_reached_where_they_are_published() {
+        let binding = |ip: &str, port: u16| json!({ "IP": ip, "PrivatePort": 80, "PublicPort": port, "Type": "tcp" });
+        let with_ports = |name: &str, ports: Vec<serde_json::Value>| {
+            let mut container = testing::container(name, 1, json!({}));
+            container["Ports"] = json!(ports);
+            container
+        };
+
+        let (url, _) = testing::docker(json!([
+            with_ports("http-any4", vec![binding("0.0.0.0", 8001)]),
+            with_ports("http-any6", vec![binding("::", 8002)]),
+            with_ports("http-one4", vec![binding("10.1.2.3", 8003)]),
+            with_ports("http-one6", vec![binding("fd00::1", 8004)]),
+            with_ports(
+                "http-both",
+                vec![binding("10.1.2.3", 8005), binding("0.0.0.0", 8006)],
+            ),
+            with_ports(
+                "http-spread",
+                vec![binding("0.0.0.0", 8007), binding("10.1.2.3", 8008)],
+            ),
+        ]))
+        .await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        for (service, addr) in [
+            ("any4", "127.0.0.1:8001"),
+            ("any6", "127.0.0.1:8002"),
+            ("one4", "10.1.2.3:8003"),
+            ("one6", "[fd00::1]:8004"),
+            ("both", "127.0.0.1:8006"),
+            ("spread", "127.0.0.1:8007"),
+        ] {
+            assert_eq!(found(&discovery, &config, service).addr, addr, "{service}");
+        }
+    }
+
+    /// What a strict lookup for `service` makes of `names`: the backend's name,
+    /// or the services it couldn't choose between.
+    async fn strictly(names: &[&str], service: &str) -> Result<String, Vec<String>> {
+        let containers = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| testing::container(name, 1000 + i as u16, json!({})))
+            .collect::<Vec<_>>();
+        let (url, _) = testing::docker(json!(containers)).await;
+        let mut config = testing::config(&url);
+        config.strict_match = true;
+        let discovery = testing::discovery(&config).await;
+
+        let headers = HeaderMap::new();
+        match dest_host_for_service(&discovery, &config, "", &headers, None, service, None) {
+            Ok(Lookup::Found(backend)) => Ok(backend.name),
+            Ok(Lookup::Ambiguous(services)) => Err(services),
+            _ => panic!("nothing for {service}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replicas_beside_their_service_are_not_ambiguous() {
+        let names = ["http-api", "http-api-2", "http-api-17"];
+        assert!(strictly(&names, "api").await.is_ok());
+
+        let names = ["http-prod-api", "http-prod-api-2"];
+        assert!(strictly(&names, "api").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn numbered_services_on_their_own_are_their_own() {
+        assert_eq!(
+            strictly(&["http-api-2", "http-api-3"], "api").await,
+            Err(vec!["api-2".to_string(), "api-3".to_string()])
+        );
+
+        // Asked for by name, a numbered service is just that service
+        assert_eq!(
+            strictly(&["http-api", "http-api-2"], "api-2").await,
+            Ok("/http-api-2".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn only_dash_numbers_are_replica_numbers() {
+        assert_eq!(
+            strictly(&["http-s", "http-s3"], "s").await,
+            Err(vec!["s".to_string(), "s3".to_string()])
+        );
+        assert_eq!(
+            strictly(&["http-web", "http-web2"], "web").await,
+            Err(vec!["web".to_string(), "web2".to_string()])
+        );
+        assert_eq!(
+            strictly(&["http-api", "http-api-x"], "api").await,
+            Err(vec!["api".to_string(), "api-x".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn aliases_are_followed() {
+        let mut placeholder = testing::container("http-old", 1, json!({ ALIAS_LABEL: "new" }));
+        placeholder["State"] = json!("created");
+        let (url, _) = testing::docker(json!([
+            placeholder,
+            testing::container("http-new", 2, json!({})),
+        ]))
+        .await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        let mut explain = Explanation::default();
+        let headers = HeaderMap::new();
+        let lookup = dest_host_for_service(
+            &discovery,
+            &config,
+            "",
+            &headers,
+            None,
+            "old",
+            Some(&mut explain),
+        );
+
+        let Ok(Lookup::Found(backend)) = lookup else {
+            panic!("alias not followed");
+        };
+        assert_eq!(backend.name, "/http-new");
+        assert_eq!(explain.services, ["old", "new"]);
+    }
+
+    #[tokio::test]
+    async fn alias_cycles_are_caught() {
+        let (url, _) = testing::docker(json!([
+            testing::container("http-ping", 1, json!({ ALIAS_LABEL: "pong" })),
+            testing::container("http-pong", 2, json!({ ALIAS_LABEL: "ping" })),
+        ]))
+        .await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        let headers = HeaderMap::new();
+        let lookup = dest_host_for_service(&discovery, &config, "", &headers, None, "ping", None);
+
+        let Ok(Lookup::AliasCycle(chain)) = lookup else {
+            panic!("cycle not caught");
+        };
+        assert_eq!(chain, ["ping", "pong", "ping"]);
+    }
+
+    #[tokio::test]
+    async fn draining_backends_are_a_last_resort() {
+        let (url, _) = testing::docker(json!([
+            testing::container("http-mixed", 1, json!({ WEIGHT_LABEL: "0" })),
+            testing::container("http-mixed-2", 2, json!({})),
+            testing::container("http-leaving", 3, json!({ DISABLE_LABEL: "true" })),
+            testing::container("http-leaving-2", 4, json!({ STATE_LABEL: "draining" })),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+        let headers = HeaderMap::new();
+
+        for last_resort in [false, true] {
+            config.serve_draining_last_resort = last_resort;
+
+            // Never while there's an active one
+            for _ in 0..20 {
+                assert_eq!(found(&discovery, &config, "mixed").name, "/http-mixed-2");
+            }
+
+            let lookup =
+                dest_host_for_service(&discovery, &config, "", &headers, None, "leaving", None);
+            match lookup {
+                Ok(Lookup::Found(backend)) => {
+                    assert!(last_resort);
+                    assert!(backend.name.starts_with("/http-leaving"));
+                }
+                Ok(Lookup::Down) => assert!(!last_resort),
+                _ => panic!("neither found nor down"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn services_are_found_by_label_or_name() {
+        let mut labeled = testing::container(
+            "whatever",
+            1,
+            json!({ SERVICE_LABEL: "foo", PORT_LABEL: "8080" }),
+        );
+        labeled["Ports"] = json!([
+            { "IP": "0.0.0.0", "PrivatePort": 80, "PublicPort": 8001, "Type": "tcp" },
+            { "IP": "0.0.0.0", "PrivatePort": 8080, "PublicPort": 8002, "Type": "tcp" },
+        ]);
+        let (url, _) = testing::docker(json!([
+            labeled,
+            testing::container("http-bar", 8003, json!({})),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        let found_for = |config: &Config, service: &str| {
+            let headers = HeaderMap::new();
+            match dest_host_for_service(&discovery, config, "", &headers, None, service, None) {
+                Ok(Lookup::Found(backend)) => Some(backend.addr),
+                _ => None,
+            }
+        };
+
+        for (mode, foo, bar) in [
+            (DiscoveryMode::Name, None, Some("127.0.0.1:8003")),
+            (DiscoveryMode::Label, Some("127.0.0.1:8002"), None),
+            (
+                DiscoveryMode::Both,
+                Some("127.0.0.1:8002"),
+                Some("127.0.0.1:8003"),
+            ),
+        ] {
+            config.discovery = mode;
+            assert_eq!(found_for(&config, "foo").as_deref(), foo, "{mode:?}");
+            assert_eq!(found_for(&config, "bar").as_deref(), bar, "{mode:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn swarm_services_are_routed_by_published_port() {
+        let service = |name: &str, replicas: u64, labels: serde_json::Value| {
+            json!({
+                "ID": name,
+                "Spec": {
+                    "Name": name,
+                    "Labels": labels,
+                    "Mode": { "Replicated": { "Replicas": replicas } },
+                },
+                "Endpoint": {
+                    "Ports": [
+                        { "Protocol": "udp", "TargetPort": 53, "PublishedPort": 30053 },
+                        { "Protocol": "tcp", "TargetPort": 80, "PublishedPort": 30080 },
+                    ],
+                },
+            })
+        };
+        let (url, requests) = testing::docker(json!([
+            service("http-foo", 3, json!({})),
+            service("http-idle", 0, json!({})),
+            service("web_bar", 1, json!({ SERVICE_LABEL: "bar" })),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        config.swarm = true;
+        config.discovery = DiscoveryMode::Both;
+        let discovery = testing::discovery(&config).await;
+
+        assert!(requests.lock().unwrap().iter().any(|r| r
+            .target
+            .split('?')
+            .next()
+            .unwrap()
+            .ends_with("/services")));
+
+        let foo = found(&discovery, &config, "foo");
+        assert_eq!(
+            (foo.name.as_str(), foo.addr.as_str()),
+            ("/http-foo", "127.0.0.1:30080")
+        );
+        assert_eq!(found(&discovery, &config, "bar").name, "/web_bar");
+
+        let headers = HeaderMap::new();
+        let idle = dest_host_for_service(&discovery, &config, "", &headers, None, "idle", None);
+        assert!(matches!(idle, Ok(Lookup::Down)));
+    }
+
+    #[tokio::test]
+    async fn states_decide_who_gets_picked() {
+        let (url, _) = testing::docker(json!([
+            testing::container("http-states", 1, json!({ STATE_LABEL: "active" })),
+            testing::container("http-states-2", 2, json!({ STATE_LABEL: "disabled" })),
+            testing::container("http-states-3", 3, json!({ STATE_LABEL: "draining" })),
+            testing::container("http-states-4", 4, json!({ WEIGHT_LABEL: "3" })),
+            testing::container("http-off", 5, json!({ STATE_LABEL: "disabled" })),
+            testing::container("http-off-2", 6, json!({ STATE_LABEL: "disabled" })),
+            testing::container("http-last", 7, json!({ STATE_LABEL: "disabled" })),
+            testing::container("http-last-2", 8, json!({ STATE_LABEL: "draining" })),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        config.serve_draining_last_resort = true;
+        let discovery = testing::discovery(&config).await;
+        let headers = HeaderMap::new();
+
+        let mut explain = Explanation::default();
+        dest_host_for_service(
+            &discovery,
+            &config,
+            "",
+            &headers,
+            None,
+            "states",
+            Some(&mut explain),
+        )
+        .unwrap();
+        let eligible: Vec<_> = explain
+            .eligible
+            .iter()
+            .map(|e| (e.name.as_str(), e.weight, e.draining))
+            .collect();
+        assert_eq!(
+            eligible,
+            [
+                ("/http-states", 1, false),
+                ("/http-states-3", 1, true),
+                ("/http-states-4", 3, false),
+            ]
+        );
+
+        // Only the active ones, about in proportion to their weights
+        let mut heavy = 0;
+        for _ in 0..400 {
+            match found(&discovery, &config, "states").name.as_str() {
+                "/http-states" => {}
+                "/http-states-4" => heavy += 1,
+                other => panic!("picked {other}"),
+            }
+        }
+        assert!((240..=360).contains(&heavy), "{heavy} of 400");
+
+        // Draining beats disabled as a last resort, and disabled is never used
+        for _ in 0..20 {
+            assert_eq!(found(&discovery, &config, "last").name, "/http-last-2");
+        }
+        let lookup = dest_host_for_service(&discovery, &config, "", &headers, None, "off", None);
+        assert!(matches!(lookup, Ok(Lookup::Down)));
+    }
+
+    fn replica(name: &str, weight: u32) -> Backend {
+        Backend {
+            name: format!("/{name}"),
+            addr: "127.0.0.1:80".to_string(),
+            docker_host: "local".to_string(),
+            labels: HashMap::from([(WEIGHT_LABEL.to_string(), weight.to_string())]),
+        }
+    }
+
+    /// The `n`th of as many clients as are needed.
+    fn client(n: u32) -> IpAddr {
+        IpAddr::from([10, 0, (n >> 8) as u8, n as u8])
+    }
+
+    /// Who `by_hash` sends each of a thousand clients to.
+    fn assignments(names: &[&str], weighted: bool) -> Vec<String> {
+        (0..1000u32)
+            .map(|n| {
+                let backends = names.iter().map(|name| replica(name, 1)).collect();
+                by_hash(backends, client(n), weighted).unwrap().name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hashes_are_the_same_everywhere() {
+        assert_eq!(stable_hash(b""), 0xf52a_15e9_a9b5_e89b);
+        assert_eq!(stable_hash(b"10.0.0.1 /http-web"), 0xaabb_e90f_d85b_d3da);
+    }
+
+    #[test]
+    fn clients_stay_put_when_backends_come_and_go() {
+        let four = ["http-web", "http-web-2", "http-web-3", "http-web-4"];
+        let before = assignments(&four, true);
+
+        // The order they're listed in doesn't matter
+        let mut shuffled = four;
+        shuffled.reverse();
+        assert_eq!(assignments(&shuffled, true), before);
+
+        // Only clients moving to the new one move, about a fifth of them
+        let after = assignments(&[&four[..], &["http-web-5"]].concat(), true);
+        let moved = before.iter().zip(&after).filter(|(b, a)| b != a);
+        assert!(moved.clone().all(|(_, a)| a == "/http-web-5"));
+        assert!((140..=260).contains(&moved.count()));
+
+        // Only the removed one's clients move
+        let after = assignments(&four[1..], true);
+        for (before, after) in before.iter().zip(&after) {
+            if before != "/http-web" {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn hashing_honors_weights() {
+        let heavy = (0..1000u32)
+            .filter(|&n| {
+                let backends = vec![replica("http-light", 1), replica("http-heavy", 3)];
+                by_hash(backends, client(n), true).unwrap().name == "/http-heavy"
+            })
+            .count();
+        assert!((680..=820).contains(&heavy), "{heavy} of 1000");
+
+        // Draining ones (picked from unweighted) are spread evenly
+        let names = ["http-a", "http-b"];
+        let to_a = assignments(&names, false)
+            .iter()
+            .filter(|name| *name == "/http-a")
+            .count();
+        assert!((420..=580).contains(&to_a), "{to_a} of 1000");
+    }
+
+    #[tokio::test]
+    async fn only_eligible_states_are_considered() {
+        let in_state = |name: &str, port: u16, state: &str| {
+            let mut container = testing::container(name, port, json!({}));
+            container["State"] = json!(state);
+            container
+        };
+        let (url, _) = testing::docker(json!([
+            in_state("http-flappy", 1, "running"),
+            in_state("http-flappy-2", 2, "restarting"),
+            in_state("http-flappy-3", 3, "paused"),
+            in_state("http-flappy-4", 4, "exited"),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        for (states, eligible) in [
+            (&["running"][..], &["/http-flappy"][..]),
+            (
+                &["running", "restarting"],
+                &["/http-flappy", "/http-flappy-2"],
+            ),
+            (
+                &["restarting", "paused"],
+                &["/http-flappy-2", "/http-flappy-3"],
+            ),
+        ] {
+            config.eligible_states = states.iter().map(|s| s.to_string()).collect();
+
+            let mut explain = Explanation::default();
+            let headers = HeaderMap::new();
+            dest_host_for_service(
+                &discovery,
+                &config,
+                "",
+                &headers,
+                None,
+                "flappy",
+                Some(&mut explain),
+            )
+            .unwrap();
+
+            let names: Vec<_> = explain.eligible.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(names, eligible, "{states:?}");
+        }
+
+        // Left out even though it's running, and told apart from stopped ones
+        let mut explain = Explanation::default();
+        let headers = HeaderMap::new();
+        dest_host_for_service(
+            &discovery,
+            &config,
+            "",
+            &headers,
+            None,
+            "flappy",
+            Some(&mut explain),
+        )
+        .unwrap();
+        assert_eq!(
+            explain.candidates[0],
+            (
+                "/http-flappy".to_string(),
+                Some("Running, but not in DUMBROUTER_ELIGIBLE_STATES".to_string())
+            )
+        );
+        assert_eq!(explain.candidates[3].1.as_deref(), Some("Not running"));
+    }
+
+    #[tokio::test]
+    async fn weightless_backends_are_draining() {
+        let weightless = json!({ STATE_LABEL: "active", WEIGHT_LABEL: "0" });
+        let (url, _) = testing::docker(json!([
+            testing::container("http-zero", 1, weightless.clone()),
+            testing::container("http-zero-2", 2, weightless),
+            testing::container("http-some", 3, json!({})),
+            testing::container("http-some-2", 4, json!({ WEIGHT_LABEL: "0" })),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+        let headers = HeaderMap::new();
+
+        // Not down just because there's no weight to go around
+        config.serve_draining_last_resort = true;
+        for _ in 0..20 {
+            assert!(found(&discovery, &config, "zero")
+                .name
+                .starts_with("/http-zero"));
+            assert_eq!(found(&discovery, &config, "some").name, "/http-some");
+        }
+
+        config.serve_draining_last_resort = false;
+        let lookup = dest_host_for_service(&discovery, &config, "", &headers, None, "zero", None);
+        assert!(matches!(lookup, Ok(Lookup::Down)));
+    }
+
+    /// For when nothing should be left to chance.
+    struct NoRng;
+
+    impl rand::RngCore for NoRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("picked at random")
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            panic!("picked at random")
+        }
+
+        fn fill_bytes(&mut self, _: &mut [u8]) {
+            panic!("picked at random")
+        }
+
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+            panic!("picked at random")
+        }
+    }
+
+    #[test]
+    fn lone_backends_are_picked_without_chance() {
+        let mut config = testing::config("http://docker.invalid");
+        config.serve_draining_last_resort = true;
+
+        let draining = || {
+            let mut backend = replica("http-old", 1);
+            backend
+                .labels
+                .insert(STATE_LABEL.to_string(), "draining".to_string());
+            backend
+        };
+
+        for backends in [
+            vec![replica("http-web", 5)],
+            vec![replica("http-web", 1), draining()],
+            vec![draining()],
+        ] {
+            let only = backends[0].name.clone();
+            let picked = select(backends, None, &config, &mut NoRng).unwrap();
+            assert_eq!(picked.name, only);
+        }
+
+        assert!(select(vec![], None, &config, &mut NoRng).is_none());
+    }
+
+    #[tokio::test]
+    async fn gone_backends_are_forgotten() {
+        let labeled = |backend_host: &str| {
+            testing::container(
+                "http-labeled",
+                3,
+                json!({ BACKEND_HOST_LABEL: backend_host }),
+            )
+        };
+        let listing = testing::Listing::new(std::sync::Mutex::new(json!([
+            testing::container("http-gone", 1, json!({})),
+            testing::container("http-gone-2", 2, json!({})),
+            labeled("10.1.1.1"),
+        ])));
+        let (url, _) = testing::docker_with(listing.clone(), Duration::ZERO).await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+        let generation = discovery.generation();
+
+        let mut picked = HashSet::new();
+        for _ in 0..50 {
+            picked.insert(found(&discovery, &config, "gone").name);
+        }
+        assert_eq!(picked.len(), 2);
+
+        // Nothing gone, nothing forgotten
+        discovery.refresh().await.unwrap();
+        assert_eq!(discovery.generation(), generation);
+        listing
+            .lock()
+            .unwrap()
+            .as_array_mut()
+            .unwrap()
+            .push(testing::container("http-new", 4, json!({})));
+        discovery.refresh().await.unwrap();
+        assert_eq!(discovery.generation(), generation);
+
+        // Reached somewhere else on the same port
+        listing.lock().unwrap()[2] = labeled("10.2.2.2");
+        discovery.refresh().await.unwrap();
+        assert!(discovery.generation() > generation);
+        let generation = discovery.generation();
+
+        listing.lock().unwrap().as_array_mut().unwrap().remove(0);
+        discovery.refresh().await.unwrap();
+        assert!(discovery.generation() > generation);
+        for _ in 0..20 {
+            assert_eq!(found(&discovery, &config, "gone").name, "/http-gone-2");
+        }
+    }
+
+    #[test]
+    fn backend_hosts_are_pinned_to_a_family() {
+        // v6 first, as resolvers tend to put it
+        let both = |_: &str| Ok(vec!["::1".parse().unwrap(), "127.0.0.1".parse().unwrap()]);
+        let v4 = |_: &str| Ok(vec!["127.0.0.1".parse().unwrap()]);
+
+        let pinned = pin_family("dual.internal", IpFamily::V4, &both);
+        assert_eq!(pinned.as_deref(), Ok("127.0.0.1"));
+        let pinned = pin_family("dual.internal", IpFamily::V6, &both);
+        assert_eq!(pinned.as_deref(), Ok("[::1]"));
+
+        let pinned = pin_family("old.internal", IpFamily::V6, &v4);
+        assert_eq!(pinned, Err("old.internal has no IPv6 address".to_string()));
+
+        // A literal is all the addresses it has
+        let pinned = pin_family("[::1]", IpFamily::V6, &resolve);
+        assert_eq!(pinned.as_deref(), Ok("[::1]"));
+        assert!(pin_family("[::1]", IpFamily::V4, &resolve).is_err());
+    }
+
+    #[tokio::test]
+    async fn podman_containers_are_backends() {
+        let (url, _) = testing::docker(json!([testing::podman_container(
+            "http-pod",
+            8080,
+            json!({})
+        )]))
+        .await;
+        let config = testing::config(&url);
+        let discovery = testing::discovery(&config).await;
+
+        let listed = discovery.snapshot.lock().unwrap().clone().unwrap().1;
+        let Ok(backend) = as_backend(&listed[0], &discovery.hosts, &config) else {
+            panic!("Podman's container was skipped");
+        };
+        assert_eq!(backend.addr, "127.0.0.1:8080");
+        assert_eq!(found(&discovery, &config, "pod").addr, "127.0.0.1:8080");
+    }
+}
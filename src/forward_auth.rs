@@ -0,0 +1,135 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Config;
+use crate::discovery::Backend;
+use crate::headers::{connection_headers, is_hop_by_hop};
+use actix_web::http::{Method, StatusCode};
+use actix_web::HttpResponse;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, HOST};
+use reqwest::redirect::Policy;
+
+/// Label sending requests for a container through the forward-auth endpoint
+/// at its value, instead of `DUMBROUTER_FORWARD_AUTH`'s.  `off` turns it off
+/// for the container.
+const FORWARD_AUTH_LABEL: &str = "dumbrouter.forward_auth";
+
+/// What the forward-auth endpoint said about a request.
+pub enum Verdict {
+    /// Send it on, with these headers added
+    Allow(Vec<(HeaderName, HeaderValue)>),
+    /// Answer with this instead, usually a redirect to a login page
+    Deny(HttpResponse),
+}
+
+/// Asks an authentication service (Authelia, oauth2-proxy and so on) about
+/// every request before it's sent on, as other proxies' forward auth does.
+pub struct ForwardAuth {
+    client: reqwest::Client,
+}
+
+impl ForwardAuth {
+    pub fn new(config: &Config) -> Self {
+        ForwardAuth {
+            client: reqwest::Client::builder()
+                .timeout(config.forward_auth_timeout)
+                // A redirect is the answer, not something to follow
+                .redirect(Policy::none())
+                .build()
+                .expect("forward-auth client can always be built"),
+        }
+    }
+
+    /// The forward-auth endpoint for requests to `backend`, if any.
+    pub fn endpoint<'a>(backend: &'a Backend, config: &'a Config) -> Option<&'a str> {
+        match backend.label(FORWARD_AUTH_LABEL) {
+            Some("off") => None,
+            Some(url) => Some(url),
+            None => config.forward_auth.as_deref(),
+        }
+    }
+
+    /// Asks `endpoint` about a `method` request for `uri` on `host`, with the
+    /// headers that are going to be sent on.  Any 2xx lets it through, with
+    /// the headers in `DUMBROUTER_FORWARD_AUTH_HEADERS` copied from the
+    /// answer.
+    pub async fn check(
+        &self,
+        endpoint: &str,
+        method: &Method,
+        host: &str,
+        uri: &str,
+        headers: &HeaderMap,
+        config: &Config,
+    ) -> Result<Verdict, reqwest::Error> {
+        // The auth service is addressed by its own name, the request's is in
+        // `X-Forwarded-Host`
+        let mut headers = headers.clone();
+        headers.remove(HOST);
+
+        let proto = headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("http");
+        let url = format!("{proto}://{host}{uri}");
+
+        // Both the names Traefik uses and the ones nginx setups tend to
+        for (name, value) in [
+            ("x-forwarded-method", method.as_str()),
+            ("x-forwarded-uri", uri),
+            ("x-original-method", method.as_str()),
+            ("x-original-url", &url),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.insert(name, value);
+            }
+        }
+
+        let res = self.client.get(endpoint).headers(headers).send().await?;
+
+        if res.status().is_success() {
+            let copied = config
+                .forward_auth_headers
+                .iter()
+                .filter_map(|name| {
+                    let name = HeaderName::try_from(name.as_str()).ok()?;
+                    let value = res.headers().get(&name)?.clone();
+                    Some((name, value))
+                })
+                .collect();
+
+            return Ok(Verdict::Allow(copied));
+        }
+
+        // Passed on as-is, cookies and all, so that logging in works
+        let status = StatusCode::from_u16(res.status().as_u16())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut answer = HttpResponse::build(status);
+        let connection = connection_headers(res.headers().get_all(CONNECTION).iter());
+
+        for (name, value) in res.headers() {
+            if name == "content-length" || name == "transfer-encoding" {
+                continue;
+            }
+
+            if !is_hop_by_hop(name.as_str(), &connection) {
+                answer.append_header((name, value));
+            }
+        }
+
+        Ok(Verdict::Deny(answer.body(res.bytes().await?)))
+    }
+}
@@ -0,0 +1,310 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::Config;
+use crate::discovery::Backend;
+use actix_web::http::header::{self as actix_header, HeaderValue};
+use reqwest::header::{HeaderMap, HeaderName};
+use std::net::IpAddr;
+
+/// Label naming the domain to put in the `Domain` attribute of the cookies a
+/// backend sets, for backends that think they live somewhere else.
+pub const COOKIE_DOMAIN_LABEL: &str = "dumbrouter.cookie_domain";
+
+/// Label prefix for headers added to every request sent to a container, e.g.
+/// `dumbrouter.req_header.X-Api-Key=secret`.
+pub const REQ_HEADER_PREFIX: &str = "dumbrouter.req_header.";
+
+/// Label prefix for headers added to every response from a container, e.g.
+/// `dumbrouter.resp_header.X-Frame-Options=DENY`.
+pub const RESP_HEADER_PREFIX: &str = "dumbrouter.resp_header.";
+
+/// The headers named by `backend`'s labels starting with `prefix`, skipping
+/// (and complaining about) any that aren't valid headers.
+pub fn label_headers(backend: &Backend, prefix: &str) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers = vec![];
+
+    for (label, value) in &backend.labels {
+        let Some(name) = label.strip_prefix(prefix) else {
+            continue;
+        };
+
+        match (HeaderName::try_from(name), HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => headers.push((name, value)),
+            _ => log!(
+                "WARN: Container {} has a bad header label {label:?}",
+                backend.name
+            ),
+        }
+    }
+
+    headers
+}
+
+/// Headers describing a verified TLS client certificate.  dumbrouter doesn't
+/// terminate TLS, so nothing has verified one and clients can't be allowed to
+/// claim otherwise.
+pub const CLIENT_CERT_HEADERS: [&str; 2] = ["x-client-cert-subject", "x-client-cert-fingerprint"];
+
+/// Headers about the connection itself rather than the request, which the
+/// other side of the proxy shouldn't see (as well as any that `Connection`
+/// names).  `Transfer-Encoding` is handled along with the body.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Whether `name` is hop-by-hop, given the (lowercased) headers that
+/// `Connection` said are.
+pub fn is_hop_by_hop(name: &str, connection: &[String]) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name) || connection.iter().any(|c| c == name)
+}
+
+/// The headers that a message's `Connection` header says are hop-by-hop,
+/// lowercased.
+pub fn connection_headers<'a>(values: impl Iterator<Item = &'a HeaderValue>) -> Vec<String> {
+    values
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// Keeps headers passed on (either way) within `DUMBROUTER_MAX_HEADER_BYTES`
+/// each and `DUMBROUTER_MAX_HEADERS_BYTES` all together, counting the ones
+/// that don't fit.  Only sizes need checking: the header types won't hold a
+/// name that isn't a token or a value with CR, LF (so no obs-fold) or other
+/// control characters in the first place.
+pub struct HeaderBudget {
+    each: Option<usize>,
+    left: Option<usize>,
+    pub dropped: u64,
+}
+
+impl HeaderBudget {
+    pub fn new(config: &Config) -> Self {
+        HeaderBudget {
+            each: config.max_header_bytes,
+            left: config.max_headers_bytes,
+            dropped: 0,
+        }
+    }
+
+    /// Whether `name: value` still fits, taking it off what's left if so.
+    pub fn admit(&mut self, name: &str, value: &[u8]) -> bool {
+        // As it goes over the wire, with `: ` and CRLF
+        let size = name.len() + value.len() + 4;
+
+        let fits =
+            self.each.is_none_or(|each| size <= each) && self.left.is_none_or(|left| size <= left);
+
+        if !fits {
+            self.dropped += 1;
+            return false;
+        }
+
+        if let Some(left) = &mut self.left {
+            *left -= size;
+        }
+
+        true
+    }
+}
+
+/// The request's headers, as sent on to the backend: everything but
+/// hop-by-hop headers, those in `skip` and those over `budget`, in one pass.
+/// Values share their bytes with the request's, and repeated headers stay
+/// repeated.
+pub fn upstream_headers(
+    headers: &actix_header::HeaderMap,
+    skip: &[&str],
+    budget: &mut HeaderBudget,
+) -> HeaderMap {
+    let connection = connection_headers(headers.get_all(actix_header::CONNECTION));
+    let mut upstream = HeaderMap::with_capacity(headers.len());
+
+    // HACK: actix_http::header::map::HeaderMap and reqwest::header::HeaderMap
+    // are BOTH actually http::header::map::HeaderMap.  Thanks to re-exports
+    // and similar hacks (and quite possibly a lack of Rust knowledge on my
+    // part) we can't use them interchangeably.
+    for (name, value) in headers {
+        if skip.contains(&name.as_str()) || is_hop_by_hop(name.as_str(), &connection) {
+            continue;
+        }
+
+        if !budget.admit(name.as_str(), value.as_bytes()) {
+            continue;
+        }
+
+        upstream.append(name.clone(), value.clone());
+    }
+
+    upstream
+}
+
+/// Headers saying where a request came from, which only mean anything when
+/// set by a proxy we trust.
+const FORWARDED_HEADERS: [&str; 5] = [
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "forwarded",
+];
+
+/// Tells the backend who's asking.  Whatever the client claimed is thrown
+/// away unless `trusted`, in which case `peer` is added to the chain instead.
+pub fn set_forwarded(headers: &mut HeaderMap, peer: Option<IpAddr>, host: &str, trusted: bool) {
+    if !trusted {
+        for name in FORWARDED_HEADERS {
+            headers.remove(name);
+        }
+    }
+
+    if let Some(peer) = peer {
+        let chain = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(earlier) => format!("{earlier}, {peer}"),
+            None => peer.to_string(),
+        };
+        set(headers, "x-forwarded-for", &chain);
+
+        if !headers.contains_key("x-real-ip") {
+            set(headers, "x-real-ip", &peer.to_string());
+        }
+    }
+
+    if !headers.contains_key("x-forwarded-host") {
+        set(headers, "x-forwarded-host", host);
+    }
+
+    // dumbrouter only speaks plain HTTP
+    if !headers.contains_key("x-forwarded-proto") {
+        set(headers, "x-forwarded-proto", "http");
+    }
+}
+
+fn set(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+/// A `Set-Cookie` value with its `Domain` attribute (if it has one) replaced
+/// with `domain`.  Cookies without a `Domain` are host-only and left alone.
+pub fn rewrite_cookie_domain(value: &HeaderValue, domain: &str) -> HeaderValue {
+    let Ok(value_str) = value.to_str() else {
+        return value.clone();
+    };
+
+    // The first part is the cookie itself, which may well be called `domain`
+    let rewritten = value_str
+        .split(';')
+        .enumerate()
+        .map(|(i, part)| {
+            let is_domain = i > 0
+                && part
+                    .trim_start()
+                    .get(..7)
+                    .is_some_and(|name| name.eq_ignore_ascii_case("domain="));
+
+            if is_domain {
+                format!(" Domain={domain}")
+            } else {
+                part.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+
+    HeaderValue::from_str(&rewritten).unwrap_or_else(|_| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spoofed() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("x-forwarded-for", "127.0.0.1"),
+            ("x-forwarded-host", "admin.internal"),
+            ("x-forwarded-proto", "https"),
+            ("x-real-ip", "127.0.0.1"),
+            ("forwarded", "for=127.0.0.1"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peers_cant_say_who_they_are() {
+        let mut headers = spoofed();
+        set_forwarded(
+            &mut headers,
+            "203.0.113.5".parse().ok(),
+            "foo.example.com",
+            false,
+        );
+
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.5");
+        assert_eq!(headers["x-real-ip"], "203.0.113.5");
+        assert_eq!(headers["x-forwarded-host"], "foo.example.com");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert!(!headers.contains_key("forwarded"));
+    }
+
+    #[test]
+    fn trusted_proxies_are_added_to_the_chain() {
+        let mut headers = spoofed();
+        set_forwarded(
+            &mut headers,
+            "10.0.0.2".parse().ok(),
+            "foo.example.com",
+            true,
+        );
+
+        assert_eq!(headers["x-forwarded-for"], "127.0.0.1, 10.0.0.2");
+        assert_eq!(headers["x-real-ip"], "127.0.0.1");
+        assert_eq!(headers["x-forwarded-host"], "admin.internal");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(headers["forwarded"], "for=127.0.0.1");
+    }
+
+    #[test]
+    fn cookie_domains_are_rewritten() {
+        for (cookie, rewritten) in [
+            (
+                "sid=1; Path=/; Domain=internal.local; HttpOnly",
+                "sid=1; Path=/; Domain=example.com; HttpOnly",
+            ),
+            ("sid=1;domain=.internal.local", "sid=1; Domain=example.com"),
+            // Host-only
+            ("sid=1; Path=/", "sid=1; Path=/"),
+            ("domain=x; Path=/", "domain=x; Path=/"),
+        ] {
+            let value = HeaderValue::from_static(cookie);
+            assert_eq!(rewrite_cookie_domain(&value, "example.com"), rewritten);
+        }
+    }
+}
@@ -0,0 +1,510 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::discovery::Backend;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Label turning on JWT checks for a container, with the URL of the JWKS
+/// that tokens have to be signed with a key from.
+pub const JWKS_URL_LABEL: &str = "dumbrouter.jwt.jwks_url";
+
+/// Label with the `iss` tokens must have, if any in particular.
+const ISSUER_LABEL: &str = "dumbrouter.jwt.issuer";
+
+/// Label with an `aud` tokens must have, if any in particular.
+const AUDIENCE_LABEL: &str = "dumbrouter.jwt.audience";
+
+/// Label sending a valid token's `sub` on in `SUBJECT_HEADER`.
+const FORWARD_SUBJECT_LABEL: &str = "dumbrouter.jwt.forward_subject";
+
+/// Where a token's `sub` is sent on.  Always removed from requests to
+/// containers with `dumbrouter.jwt.jwks_url`, so that clients can't set it.
+pub const SUBJECT_HEADER: &str = "x-auth-subject";
+
+/// How long fetched keys are used for before being fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A JWKS is fetched again for a key it didn't have, but not more often than
+/// this, so that made-up key IDs can't make us hammer it.
+const JWKS_MIN_REFETCH: Duration = Duration::from_secs(10);
+
+/// How long a JWKS server gets to answer.
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Leeway for `exp` and `nbf`, for clocks that are a little off.
+const LEEWAY_SECS: u64 = 30;
+
+/// The keys from one JWKS, by key ID.
+struct KeySet {
+    fetched: Instant,
+    keys: HashMap<String, PKey<Public>>,
+}
+
+/// Checks bearer tokens for containers with `dumbrouter.jwt.jwks_url`,
+/// keeping the keys of every JWKS it has fetched.
+pub struct Jwt {
+    client: reqwest::Client,
+    key_sets: Mutex<HashMap<String, KeySet>>,
+}
+
+impl Jwt {
+    pub fn new() -> Self {
+        Jwt {
+            client: reqwest::Client::builder()
+                .timeout(JWKS_TIMEOUT)
+                .build()
+                .expect("JWKS client can always be built"),
+            key_sets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// For a request with `authorization` to `backend`: `Ok(None)` if the
+    /// backend doesn't check tokens, `Ok(Some(subject))` if the token is good
+    /// (and its `sub` is to be sent on), and why not otherwise.
+    pub async fn check(
+        &self,
+        backend: &Backend,
+        authorization: Option<&str>,
+    ) -> Result<Option<Option<String>>, String> {
+        let Some(jwks_url) = backend.label(JWKS_URL_LABEL) else {
+            return Ok(None);
+        };
+
+        let token = authorization
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or("No bearer token")?;
+
+        let parts = token.split('.').collect::<Vec<_>>();
+        let [header, payload, signature] = parts[..] else {
+            return Err("Token isn't a JWT".to_string());
+        };
+
+        let signed = &token[..header.len() + 1 + payload.len()];
+        let header = decode_json(header)?;
+        let alg = header["alg"].as_str().unwrap_or_default();
+        let kid = header["kid"].as_str().unwrap_or_default();
+
+        let key = self.key(jwks_url, kid).await?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "Token signature isn't base64")?;
+
+        if !verify(alg, &key, signed.as_bytes(), &signature)? {
+            return Err("Token signature doesn't match".to_string());
+        }
+
+        let claims = decode_json(payload)?;
+        check_claims(&claims, backend)?;
+
+        let subject = match backend.label(FORWARD_SUBJECT_LABEL) {
+            Some("true") => claims["sub"].as_str().map(String::from),
+            _ => None,
+        };
+
+        Ok(Some(subject))
+    }
+
+    /// Key `kid` from the JWKS at `url`, fetching it if it's not known yet.
+    async fn key(&self, url: &str, kid: &str) -> Result<PKey<Public>, String> {
+        let refetch = {
+            let key_sets = self.key_sets.lock().unwrap();
+
+            match key_sets.get(url) {
+                Some(set) if set.fetched.elapsed() < JWKS_TTL => match set.keys.get(kid) {
+                    Some(key) => return Ok(key.clone()),
+                    None => set.fetched.elapsed() >= JWKS_MIN_REFETCH,
+                },
+                _ => true,
+            }
+        };
+
+        if refetch {
+            match self.fetch(url).await {
+                Ok(keys) => {
+                    let mut key_sets = self.key_sets.lock().unwrap();
+                    key_sets.insert(
+                        url.to_string(),
+                        KeySet {
+                            fetched: Instant::now(),
+                            keys,
+                        },
+                    );
+                }
+                // Keys we already have are still better than none
+                Err(err) => log!("WARN: {err}"),
+            }
+        }
+
+        let key_sets = self.key_sets.lock().unwrap();
+        key_sets
+            .get(url)
+            .and_then(|set| set.keys.get(kid))
+            .cloned()
+            .ok_or_else(|| format!("No key {kid:?} in {url}"))
+    }
+
+    async fn fetch(&self, url: &str) -> Result<HashMap<String, PKey<Public>>, String> {
+        let jwks = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| format!("Can't fetch {url}: {err}"))?
+            .json::<Value>()
+            .await
+            .map_err(|err| format!("Bad JWKS from {url}: {err}"))?;
+
+        let mut keys = HashMap::new();
+
+        for jwk in jwks["keys"].as_array().into_iter().flatten() {
+            let kid = jwk["kid"].as_str().unwrap_or_default().to_string();
+
+            match public_key(jwk) {
+                Ok(key) => {
+                    keys.insert(kid, key);
+                }
+                // Other keys may still be fine
+                Err(err) => log!("WARN: Skipping key {kid:?} from {url}: {err}"),
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+fn decode_json(part: &str) -> Result<Value, String> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| "Token isn't base64")?;
+
+    serde_json::from_slice(&bytes).map_err(|_| "Token isn't JSON".to_string())
+}
+
+/// The public key in `jwk`, which has to be an RSA or P-256/P-384 key.
+fn public_key(jwk: &Value) -> Result<PKey<Public>, String> {
+    let number = |name: &str| {
+        let encoded = jwk[name].as_str().ok_or(format!("No {name}"))?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| format!("Bad {name}"))?;
+        BigNum::from_slice(&bytes).map_err(|err| err.to_string())
+    };
+
+    let key = match (jwk["kty"].as_str(), jwk["crv"].as_str()) {
+        (Some("RSA"), _) => {
+            let rsa = Rsa::from_public_components(number("n")?, number("e")?);
+            rsa.and_then(PKey::from_rsa)
+        }
+        (Some("EC"), Some(crv @ ("P-256" | "P-384"))) => {
+            let nid = match crv {
+                "P-256" => Nid::X9_62_PRIME256V1,
+                _ => Nid::SECP384R1,
+            };
+            let group = EcGroup::from_curve_name(nid).map_err(|err| err.to_string())?;
+            let (x, y) = (number("x")?, number("y")?);
+            let ec = EcKey::from_public_key_affine_coordinates(&group, &x, &y);
+            ec.and_then(PKey::from_ec_key)
+        }
+        (kty, crv) => return Err(format!("Unsupported key type {kty:?} {crv:?}")),
+    };
+
+    key.map_err(|err| err.to_string())
+}
+
+/// Whether `signature` is `key`'s over `signed`, with `alg` (one of RS256,
+/// RS384, RS512, ES256 and ES384).
+fn verify(alg: &str, key: &PKey<Public>, signed: &[u8], signature: &[u8]) -> Result<bool, String> {
+    let (digest, curve) = match alg {
+        "RS256" => (MessageDigest::sha256(), None),
+        "RS384" => (MessageDigest::sha384(), None),
+        "RS512" => (MessageDigest::sha512(), None),
+        "ES256" => (MessageDigest::sha256(), Some(Nid::X9_62_PRIME256V1)),
+        "ES384" => (MessageDigest::sha384(), Some(Nid::SECP384R1)),
+        // Notably `none`, and HMAC, which would need a shared secret
+        _ => return Err(format!("Unsupported algorithm {alg:?}")),
+    };
+
+    // The key has to be of the kind (and on the curve) the algorithm says, or
+    // a key could be passed off as something else
+    let fits = match (curve, key.ec_key()) {
+        (None, Err(_)) => key.rsa().is_ok(),
+        (Some(curve), Ok(ec)) => ec.group().curve_name() == Some(curve),
+        _ => false,
+    };
+    if !fits {
+        return Err(format!("Key doesn't fit algorithm {alg:?}"));
+    }
+
+    // JWTs have ECDSA signatures as the bare `r` and `s`, OpenSSL wants DER
+    let signature = if curve.is_some() {
+        let (r, s) = signature.split_at(signature.len() / 2);
+        BigNum::from_slice(r)
+            .and_then(|r| Ok((r, BigNum::from_slice(s)?)))
+            .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+            .and_then(|sig| sig.to_der())
+            .map_err(|err| err.to_string())?
+    } else {
+        signature.to_vec()
+    };
+
+    let mut verifier = Verifier::new(digest, key).map_err(|err| err.to_string())?;
+    verifier.update(signed).map_err(|err| err.to_string())?;
+
+    // A malformed signature is just a wrong one
+    Ok(verifier.verify(&signature).unwrap_or(false))
+}
+
+/// Checks `exp` (which is required), `nbf`, and `iss` and `aud` when
+/// `backend`'s labels ask for them.
+fn check_claims(claims: &Value, backend: &Backend) -> Result<(), String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    match claims["exp"].as_u64() {
+        Some(exp) if exp.saturating_add(LEEWAY_SECS) < now => {
+            return Err("Token has expired".to_string())
+        }
+        Some(_) => {}
+        None => return Err("Token has no exp".to_string()),
+    }
+
+    if claims["nbf"]
+        .as_u64()
+        .is_some_and(|nbf| nbf > now.saturating_add(LEEWAY_SECS))
+    {
+        return Err("Token isn't valid yet".to_string());
+    }
+
+    if let Some(issuer) = backend.label(ISSUER_LABEL) {
+        if claims["iss"].as_str() != Some(issuer) {
+            return Err("Token is from the wrong issuer".to_string());
+        }
+    }
+
+    if let Some(audience) = backend.label(AUDIENCE_LABEL) {
+        let matches = match &claims["aud"] {
+            Value::String(aud) => aud == audience,
+            Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+            _ => false,
+        };
+
+        if !matches {
+            return Err("Token is for a different audience".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNumContext;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+    use serde_json::json;
+
+    fn b64(bytes: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn backend(labels: &[(&str, &str)]) -> Backend {
+        Backend {
+            name: "/http-foo".to_string(),
+            addr: "127.0.0.1:80".to_string(),
+            docker_host: "docker".to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    fn rsa_key() -> (PKey<Private>, Value) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwk = json!({
+            "kty": "RSA",
+            "n": b64(&rsa.n().to_vec()),
+            "e": b64(&rsa.e().to_vec()),
+        });
+
+        (PKey::from_rsa(rsa).unwrap(), jwk)
+    }
+
+    fn ec_key(nid: Nid, crv: &str) -> (PKey<Private>, Value) {
+        let group = EcGroup::from_curve_name(nid).unwrap();
+        let ec = EcKey::generate(&group).unwrap();
+
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        let mut ctx = BigNumContext::new().unwrap();
+        ec.public_key()
+            .affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx)
+            .unwrap();
+        let jwk = json!({ "kty": "EC", "crv": crv, "x": b64(&x.to_vec()), "y": b64(&y.to_vec()) });
+
+        (PKey::from_ec_key(ec).unwrap(), jwk)
+    }
+
+    /// `key`'s signature over `signed`, as a JWT has it.
+    fn sign(key: &PKey<Private>, digest: MessageDigest, signed: &[u8]) -> Vec<u8> {
+        let mut signer = Signer::new(digest, key).unwrap();
+        signer.update(signed).unwrap();
+        let der = signer.sign_to_vec().unwrap();
+
+        let Ok(ec) = key.ec_key() else {
+            return der;
+        };
+
+        // Back from DER to `r` and `s`, each padded to the curve's size
+        let size = (ec.group().degree() as i32 + 7) / 8;
+        let sig = EcdsaSig::from_der(&der).unwrap();
+        let mut raw = sig.r().to_vec_padded(size).unwrap();
+        raw.extend(sig.s().to_vec_padded(size).unwrap());
+        raw
+    }
+
+    fn public(jwk: &Value) -> PKey<Public> {
+        public_key(jwk).unwrap()
+    }
+
+    #[test]
+    fn rs256_signatures_are_checked() {
+        let (key, jwk) = rsa_key();
+        let signature = sign(&key, MessageDigest::sha256(), b"head.body");
+
+        assert_eq!(
+            verify("RS256", &public(&jwk), b"head.body", &signature),
+            Ok(true)
+        );
+        assert_eq!(
+            verify("RS256", &public(&jwk), b"head.other", &signature),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn es256_signatures_are_checked() {
+        let (key, jwk) = ec_key(Nid::X9_62_PRIME256V1, "P-256");
+        let signature = sign(&key, MessageDigest::sha256(), b"head.body");
+
+        assert_eq!(
+            verify("ES256", &public(&jwk), b"head.body", &signature),
+            Ok(true)
+        );
+
+        let mut bad = signature.clone();
+        bad[10] ^= 1;
+        assert_eq!(
+            verify("ES256", &public(&jwk), b"head.body", &bad),
+            Ok(false)
+        );
+        assert_eq!(
+            verify("ES256", &public(&jwk), b"head.body", b"junk"),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn keys_have_to_fit_the_algorithm() {
+        let (rsa, rsa_jwk) = rsa_key();
+        let (p256, p256_jwk) = ec_key(Nid::X9_62_PRIME256V1, "P-256");
+        let (_, p384_jwk) = ec_key(Nid::SECP384R1, "P-384");
+
+        let signature = sign(&rsa, MessageDigest::sha256(), b"head.body");
+        assert!(verify("ES256", &public(&rsa_jwk), b"head.body", &signature).is_err());
+
+        let signature = sign(&p256, MessageDigest::sha256(), b"head.body");
+        assert!(verify("RS256", &public(&p256_jwk), b"head.body", &signature).is_err());
+        assert!(verify("ES384", &public(&p256_jwk), b"head.body", &signature).is_err());
+        assert!(verify("ES256", &public(&p384_jwk), b"head.body", &signature).is_err());
+
+        for alg in ["none", "HS256", ""] {
+            assert!(verify(alg, &public(&p256_jwk), b"head.body", &signature).is_err());
+        }
+    }
+
+    #[test]
+    fn only_rsa_and_nist_curve_keys_are_taken() {
+        let (_, mut jwk) = ec_key(Nid::X9_62_PRIME256V1, "P-256");
+        jwk["crv"] = json!("P-521");
+        assert!(public_key(&jwk).is_err());
+
+        assert!(public_key(&json!({ "kty": "oct", "k": "c2VjcmV0" })).is_err());
+        assert!(public_key(&json!({ "kty": "RSA", "n": "!!", "e": "AQAB" })).is_err());
+    }
+
+    #[test]
+    fn expired_tokens_are_turned_away() {
+        let backend = backend(&[]);
+
+        assert!(check_claims(&json!({ "exp": now() + 60 }), &backend).is_ok());
+        // Within the leeway
+        assert!(check_claims(&json!({ "exp": now() - 10 }), &backend).is_ok());
+        assert!(check_claims(&json!({ "exp": now() - 120 }), &backend).is_err());
+        assert!(check_claims(&json!({}), &backend).is_err());
+        assert!(check_claims(&json!({ "exp": u64::MAX }), &backend).is_ok());
+    }
+
+    #[test]
+    fn tokens_arent_taken_before_nbf() {
+        let backend = backend(&[]);
+        let exp = now() + 600;
+
+        assert!(check_claims(&json!({ "exp": exp, "nbf": now() - 60 }), &backend).is_ok());
+        assert!(check_claims(&json!({ "exp": exp, "nbf": now() + 10 }), &backend).is_ok());
+        assert!(check_claims(&json!({ "exp": exp, "nbf": now() + 120 }), &backend).is_err());
+        assert!(check_claims(&json!({ "exp": exp, "nbf": u64::MAX }), &backend).is_err());
+    }
+
+    #[test]
+    fn issuer_and_audience_are_checked_when_labeled() {
+        let backend = backend(&[(ISSUER_LABEL, "me"), (AUDIENCE_LABEL, "foo")]);
+        let exp = now() + 600;
+
+        let good = json!({ "exp": exp, "iss": "me", "aud": ["bar", "foo"] });
+        assert!(check_claims(&good, &backend).is_ok());
+
+        let wrong_iss = json!({ "exp": exp, "iss": "them", "aud": "foo" });
+        assert!(check_claims(&wrong_iss, &backend).is_err());
+
+        let wrong_aud = json!({ "exp": exp, "iss": "me", "aud": "bar" });
+        assert!(check_claims(&wrong_aud, &backend).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::{Config, LogFormat, LogLevel};
+use crate::context::RequestContext;
+use actix_web::http::StatusCode;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Lines waiting to be written before new ones start getting dropped.
+const QUEUE_LINES: usize = 8192;
+
+/// Writes an `INFO:`/`WARN:`/`ERROR:` line to the error log (stderr unless
+/// `DUMBROUTER_LOG_FILE` is set).
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::error_line(format!($($arg)*))
+    };
+}
+
+static ERROR_LOG: OnceLock<Option<Sink>> = OnceLock::new();
+static ACCESS_LOG: OnceLock<Option<Sink>> = OnceLock::new();
+static ACCESS_LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+static LEVEL: OnceLock<LogLevel> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    /// This thread's error log lines, while a test is keeping them
+    static CAPTURED: std::cell::RefCell<Option<Vec<String>>> = Default::default();
+}
+
+/// Opens the log files, if any.  Until this is called everything goes to
+/// stderr.
+pub fn init(config: &Config) -> Result<(), String> {
+    let open = |path: &Option<String>| match path.as_deref() {
+        None => Ok(None),
+        Some("-") => Ok(Some(Sink::stdout())),
+        Some(path) => Sink::file(path, config.log_rotate_bytes, config.log_keep).map(Some),
+    };
+
+    let _ = ERROR_LOG.set(open(&config.log_file)?);
+    let _ = ACCESS_LOG.set(open(&config.access_log_file)?);
+    let _ = ACCESS_LOG_FORMAT.set(config.log_format);
+    let _ = LEVEL.set(config.log_level);
+
+    Ok(())
+}
+
+pub fn error_line(line: String) {
+    if LEVEL.get() == Some(&LogLevel::Error) && !line.starts_with("ERROR") {
+        return;
+    }
+
+    #[cfg(test)]
+    if CAPTURED.with(|captured| captured.borrow().is_some()) {
+        CAPTURED.with(|captured| captured.borrow_mut().as_mut().unwrap().push(line));
+        return;
+    }
+
+    match ERROR_LOG.get() {
+        Some(Some(sink)) => sink.send(line),
+        _ => eprintln!("{line}"),
+    }
+}
+
+/// Keeps this thread's error log lines from here on, for `captured`.
+#[cfg(test)]
+pub fn capture() {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(vec![]));
+}
+
+/// The lines kept since `capture`, which stops keeping them.
+#[cfg(test)]
+pub fn captured() -> Vec<String> {
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
+
+/// Records a finished request in the access log, if there is one.
+pub fn access(ctx: &RequestContext, status: StatusCode, bytes: Option<u64>) {
+    let Some(Some(sink)) = ACCESS_LOG.get() else {
+        return;
+    };
+
+    let format = ACCESS_LOG_FORMAT.get().copied().unwrap_or(LogFormat::Plain);
+    sink.send(access_line(ctx, status, bytes, format, SystemTime::now()));
+}
+
+/// A finished request's access log line, as of `now`.
+fn access_line(
+    ctx: &RequestContext,
+    status: StatusCode,
+    bytes: Option<u64>,
+    format: LogFormat,
+    now: SystemTime,
+) -> String {
+    if format == LogFormat::Json {
+        // Every field is always there, null if it doesn't apply
+        let record = json!({
+            "timestamp": rfc3339_timestamp(now),
+            "client_ip": ctx.client_ip,
+            "host": ctx.host,
+            "method": ctx.method.as_str(),
+            "path": ctx.path,
+            "query": ctx.query,
+            "service": ctx.service,
+            "backend": ctx.backend,
+            "status": status.as_u16(),
+            "bytes_sent": bytes,
+            "duration_ms": millis(ctx.started.elapsed()),
+            "discovery_duration_ms": ctx.discovery_time.map(millis),
+            "upstream_duration_ms": ctx.upstream_time.map(millis),
+            "request_id": ctx.id,
+            "user_agent": ctx.user_agent,
+        });
+
+        return record.to_string();
+    }
+
+    let path = match &ctx.query {
+        Some(query) => format!("{}?{}", ctx.path, query),
+        None => ctx.path.clone(),
+    };
+
+    let ms =
+        |duration: Option<Duration>| duration.map_or("-".to_string(), |d| millis(d).to_string());
+
+    // Combined log format, plus what dumbrouter did with the request
+    format!(
+        "{} - - [{}] \"{} {} {:?}\" {} {} {:?} {:?} host={} service={} backend={} duration_ms={} discovery_ms={} upstream_ms={} [{}]",
+        ctx.client_ip.as_deref().unwrap_or("-"),
+        clf_timestamp(now),
+        ctx.method,
+        path,
+        ctx.version,
+        status.as_u16(),
+        bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+        ctx.referer.as_deref().unwrap_or("-"),
+        ctx.user_agent.as_deref().unwrap_or("-"),
+        ctx.host,
+        ctx.service.as_deref().unwrap_or("-"),
+        ctx.backend.as_deref().unwrap_or("-"),
+        ctx.started.elapsed().as_millis(),
+        ms(ctx.discovery_time),
+        ms(ctx.upstream_time),
+        ctx.id,
+    )
+}
+
+/// Somewhere to write lines to without waiting on the disk.  A thread does
+/// the actual writing; if it falls too far behind, lines are dropped (and
+/// counted) rather than holding up requests.
+struct Sink {
+    tx: SyncSender<String>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Sink {
+    fn stdout() -> Self {
+        Sink::spawn(|rx, dropped| {
+            for line in rx {
+                let mut stdout = io::stdout().lock();
+                let _ = writeln!(stdout, "{line}");
+                let _ = note_dropped(&mut stdout, &dropped);
+            }
+        })
+    }
+
+    fn file(path: &str, rotate_bytes: u64, keep: usize) -> Result<Self, String> {
+        let mut file = RotatingFile::open(path, rotate_bytes, keep)
+            .map_err(|err| format!("Can't open {path}: {err}"))?;
+
+        Ok(Sink::spawn(move |rx, dropped| {
+            for line in rx {
+                if let Err(err) = file.write_line(&line, &dropped) {
+                    eprintln!("ERROR: Can't write to {}: {err}", file.path);
+                }
+            }
+        }))
+    }
+
+    fn spawn<F>(write: F) -> Self
+    where
+        F: FnOnce(Receiver<String>, Arc<AtomicU64>) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let writer_dropped = dropped.clone();
+        thread::spawn(move || write(rx, writer_dropped));
+
+        Sink { tx, dropped }
+    }
+
+    fn send(&self, line: String) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Mentions lines that didn't make it, once the writer has caught up.
+fn note_dropped(out: &mut impl Write, dropped: &AtomicU64) -> io::Result<()> {
+    match dropped.swap(0, Ordering::Relaxed) {
+        0 => Ok(()),
+        n => writeln!(out, "WARN: Dropped {n} log lines, couldn't keep up"),
+    }
+}
+
+/// A log file that's rotated when it grows past `rotate_bytes`, or daily
+/// (UTC) when that's 0.  `path.1` is the newest old file, `path.<keep>` the
+/// oldest.
+struct RotatingFile {
+    path: String,
+    rotate_bytes: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+    day: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &str, rotate_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        // Pick up where a previous run left off, so a restart doesn't reset
+        // the daily rotation
+        let day = match file.metadata()?.modified() {
+            Ok(modified) => day(modified),
+            Err(_) => day(SystemTime::now()),
+        };
+
+        Ok(RotatingFile {
+            path: path.to_string(),
+            rotate_bytes,
+            keep,
+            file,
+            size,
+            day,
+        })
+    }
+
+    fn write_line(&mut self, line: &str, dropped: &AtomicU64) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+
+        let due = match self.rotate_bytes {
+            0 => day(SystemTime::now()) != self.day,
+            max => self.size > 0 && self.size + len > max,
+        };
+
+        if due {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+
+        note_dropped(&mut self.file, dropped)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.keep));
+
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(
+                    format!("{}.{n}", self.path),
+                    format!("{}.{}", self.path, n + 1),
+                );
+            }
+
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.day = day(SystemTime::now());
+
+        Ok(())
+    }
+}
+
+/// Down to the microsecond, no further
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+/// `10/Oct/2000:13:55:36 +0000`
+fn clf_timestamp(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (year, month, day, hour, minute, second, _) = utc(time);
+
+    format!(
+        "{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000",
+        MONTHS[month as usize - 1],
+    )
+}
+
+/// `2000-10-10T13:55:36.123Z`
+fn rfc3339_timestamp(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc(time);
+
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
+
+/// (year, month, day, hour, minute, second, millisecond)
+fn utc(time: SystemTime) -> (i64, u32, u32, u64, u64, u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+
+    (
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// (year, month, day) for a number of days since 1970-01-01, per Howard
+/// Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ctx(discovery_time: Option<Duration>) -> RequestContext {
+        let req = actix_web::test::TestRequest::get()
+            .uri("/path")
+            .to_http_request();
+        let mut ctx = RequestContext::new(&req, "foo.example.com");
+        ctx.discovery_time = discovery_time;
+        ctx
+    }
+
+    #[test]
+    fn discovery_time_is_logged() {
+        let ctx = ctx(Some(Duration::from_micros(1500)));
+
+        let line = access_line(
+            &ctx,
+            StatusCode::OK,
+            Some(5),
+            LogFormat::Plain,
+            SystemTime::now(),
+        );
+        let ms = line
+            .split(' ')
+            .find_map(|field| field.strip_prefix("discovery_ms="))
+            .unwrap_or_else(|| panic!("no discovery_ms in {line}"));
+        assert_eq!(ms.parse::<f64>(), Ok(1.5));
+
+        let line = access_line(
+            &ctx,
+            StatusCode::OK,
+            Some(5),
+            LogFormat::Json,
+            SystemTime::now(),
+        );
+        let record = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        assert_eq!(record["discovery_duration_ms"].as_f64(), Some(1.5));
+    }
+
+    #[test]
+    fn missing_discovery_time_is_left_blank() {
+        // Answered before a backend was looked for
+        let ctx = ctx(None);
+
+        let line = access_line(
+            &ctx,
+            StatusCode::OK,
+            None,
+            LogFormat::Plain,
+            SystemTime::now(),
+        );
+        assert!(line.contains(" discovery_ms=- "), "{line}");
+
+        let line = access_line(
+            &ctx,
+            StatusCode::OK,
+            None,
+            LogFormat::Json,
+            SystemTime::now(),
+        );
+        let record = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+        assert!(record["discovery_duration_ms"].is_null(), "{line}");
+    }
+}
@@ -14,239 +14,3326 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+// First, so that the `log!` macro is available everywhere else
+#[macro_use]
+mod log;
+
+mod body;
+mod cli;
+mod clients;
+mod compress;
+mod config;
+mod conn;
+mod context;
+mod discovery;
+mod forward_auth;
+mod headers;
+mod jwt;
+mod metrics;
+mod mirror;
+mod rewrite;
+#[cfg(test)]
+mod testing;
+mod trace;
+
+use actix_web::body::{BodySize, MessageBody};
 use actix_web::dev::ConnectionInfo;
-use actix_web::http::Method;
+use actix_web::http::{ConnectionType, Method, StatusCode, Version};
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use bollard::container::ListContainersOptions;
-use bollard::Docker;
-use rand::seq::IteratorRandom;
-use reqwest::header::HeaderMap;
-use std::collections::HashMap;
-use std::env;
-use std::fmt::Debug;
+use clap::Parser;
+use cli::Args;
+use clients::Clients;
+use config::{Config, ForwardHost, HostRule, Redirect};
+use conn::{unless_closed, ClientSocket};
+use context::RequestContext;
+use discovery::{
+    dest_host_for_service, Backend, Discovery, DiscoveryError, DockerHost, Explanation, Lookup,
+};
+use forward_auth::{ForwardAuth, Verdict};
+use futures_util::future::join_all;
+use futures_util::stream::{self, StreamExt};
+use headers::{
+    connection_headers, is_hop_by_hop, label_headers, rewrite_cookie_domain, set_forwarded,
+    upstream_headers, HeaderBudget, CLIENT_CERT_HEADERS, COOKIE_DOMAIN_LABEL, REQ_HEADER_PREFIX,
+    RESP_HEADER_PREFIX,
+};
+use jwt::{Jwt, SUBJECT_HEADER};
+use listenfd::ListenFd;
+use metrics::Metrics;
+use mirror::Mirror;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION,
+    CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HOST, SET_COOKIE, TRANSFER_ENCODING,
+    USER_AGENT, VARY, VIA,
+};
+use serde_json::json;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use trace::{TraceContext, Tracer};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// i can't find a better way to do this :(
+const SUPPORTED_METHODS: [Method; 6] = [
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::HEAD,
+    Method::OPTIONS,
+];
+
+/// Label choosing `http` (default) or `https` for talking to a backend.
+const SCHEME_LABEL: &str = "dumbrouter.scheme";
+
+/// Where metrics are served when `DUMBROUTER_METRICS` is set.
+const METRICS_PATH: &str = "/_dumbrouter/metrics";
+
+/// Answers 200 once containers have been listed, 503 until then.
+const READY_PATH: &str = "/_dumbrouter/ready";
+
+/// Where routing can be tried out when `DUMBROUTER_ADMIN` is set.
+const RESOLVE_PATH: &str = "/_dumbrouter/resolve";
+
+/// Where the settings in effect are shown when `DUMBROUTER_ADMIN` is set.
+const CONFIG_PATH: &str = "/_dumbrouter/config";
+
+/// Requests under this path are answered from `DUMBROUTER_ACME_DIR` when set.
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Where `POST` reloads the config file's rules and lists the containers
+/// again, when `DUMBROUTER_RELOAD_TOKEN` is set.
+const RELOAD_PATH: &str = "/_dumbrouter/reload";
+
+/// Request header carrying `DUMBROUTER_RELOAD_TOKEN`.
+const RELOAD_HEADER: &str = "x-dumbrouter-reload-token";
+
+/// Request header carrying `DUMBROUTER_DEBUG_TOKEN`, to get debug headers
+/// back for just that request.
+const DEBUG_HEADER: &str = "x-dumbrouter-debug";
+
+/// What requests whose clients went away are logged with, as nginx does.
+const CLIENT_CLOSED_STATUS: u16 = 499;
+
+/// Label making a container stand for a redirect to its value (with
+/// `$request_uri` standing for the request's path and query), rather than a
+/// backend.  It's never sent anything.
+const REDIRECT_LABEL: &str = "dumbrouter.redirect";
+
+/// Label with the status for `dumbrouter.redirect`: 301, 302 (the default),
+/// 307 or 308.
+const REDIRECT_STATUS_LABEL: &str = "dumbrouter.redirect_status";
+
+/// Label for backends that don't get HEAD right: HEAD requests are sent to
+/// them as GETs, and the body is thrown away.
+const HEAD_AS_GET_LABEL: &str = "dumbrouter.head_as_get";
+
+/// Label with the `Accept-Encoding` to send the container instead of the
+/// client's: `identity` for plain responses, `remove` for none at all, and
+/// `client` for the client's even with `DUMBROUTER_UPSTREAM_ACCEPT_ENCODING`.
+const UPSTREAM_ACCEPT_ENCODING_LABEL: &str = "dumbrouter.upstream_accept_encoding";
+
+/// Label with the biggest request body the container is sent, instead of
+/// `DUMBROUTER_MAX_BODY_BYTES`: a number of bytes, with `k`, `m` or `g` for
+/// KiB, MiB or GiB.  `0` is no limit.
+const MAX_BODY_SIZE_LABEL: &str = "dumbrouter.max_body_size";
+
+/// Label with the `Host` to send the container, like `DUMBROUTER_FORWARD_HOST`.
+const FORWARD_HOST_LABEL: &str = "dumbrouter.forward_host";
+
+/// Sent as `Retry-After` when a service's containers exist but aren't up.
+const RETRY_AFTER_SECS: u32 = 5;
+
 struct AppData {
-    docker: Docker,
-    http_client: reqwest::Client,
+    config: Config,
+    discovery: Discovery,
+    clients: Clients,
+    metrics: Metrics,
+    tracer: Option<Tracer>,
+    jwt: Jwt,
+    forward_auth: ForwardAuth,
+    mirror: Mirror,
+    /// Added to `Via` on everything we send on, to tell when a request comes
+    /// back around
+    via: String,
+    /// The addresses being listened on, once they are
+    listening: OnceLock<Vec<SocketAddr>>,
+}
+
+impl AppData {
+    fn new(config: Config, discovery: Discovery) -> Self {
+        AppData {
+            discovery,
+            tracer: config.otlp_endpoint.clone().map(Tracer::new),
+            clients: Clients::new(&config),
+            forward_auth: ForwardAuth::new(&config),
+            mirror: Mirror::new(),
+            config,
+            metrics: Metrics::default(),
+            jwt: Jwt::new(),
+            via: format!("1.1 dumbrouter-{:08x}", rand::random::<u32>()),
+            listening: OnceLock::new(),
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        // i can't find a better way to do this :(
-        let supported_methods = [
-            Method::GET,
-            Method::POST,
-            Method::PUT,
-            Method::DELETE,
-            Method::HEAD,
-            Method::OPTIONS,
-        ];
+    let args = Args::parse();
+
+    let config = Config::load(&args).unwrap_or_else(|err| {
+        eprintln!("ERROR: {err}");
+        std::process::exit(1);
+    });
+
+    log::init(&config).unwrap_or_else(|err| {
+        eprintln!("ERROR: {err}");
+        std::process::exit(1);
+    });
+
+    let docker_hosts = config
+        .docker_hosts
+        .iter()
+        .map(|endpoint| DockerHost::connect(endpoint, &config))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|err| {
+            log!("ERROR: Can't set up Docker connection: {err}");
+            std::process::exit(1);
+        });
+
+    let reachable = ping_docker_hosts(&docker_hosts, config.warmup_timeout).await;
+
+    // Built once and shared by every worker, so there's one Docker connection
+    // (per host) and one connection pool to the backends
+    let discovery = Discovery::new(docker_hosts, &config);
+    let data = web::Data::new(AppData::new(config, discovery));
+
+    // Requests get a 503 until one of them answers, which the background
+    // refresh keeps trying for
+    if reachable == 0 {
+        log!(
+            "ERROR: None of the Docker hosts can be reached, starting DEGRADED with an empty cache"
+        );
+    } else {
+        warm_cache(&data).await;
+    }
+
+    let refresher = data.clone();
+    tokio::spawn(async move { refresher.discovery.refresh_forever().await });
+
+    let listening = data.clone();
+    let max_connections = data.config.max_connections;
+    let bind = data.config.bind.clone();
+
+    let mut server = HttpServer::new(move || App::new().app_data(data.clone()).configure(routes))
+        .on_connect(conn::on_connect);
 
-        let unsupported_methods = [Method::CONNECT, Method::TRACE];
+    if let Some(max) = max_connections {
+        server = server.max_connections(max);
+    }
 
-        let mut app = App::new().app_data(web::Data::new(AppData {
-            docker: Docker::connect_with_socket_defaults().unwrap(),
-            http_client: reqwest::Client::new(),
-        }));
+    // Under systemd socket activation the sockets are already open, and
+    // every one of them gets served
+    let mut listenfd = ListenFd::from_env();
+    let mut inherited = 0;
 
-        for method in supported_methods {
-            app = app.route("{path:.*}", web::method(method).to(handler));
+    for i in 0..listenfd.len() {
+        if let Some(listener) = listenfd.take_tcp_listener(i)? {
+            server = server.listen(listener)?;
+            inherited += 1;
         }
+    }
+
+    if inherited > 0 {
+        log!("INFO: Listening on {inherited} socket(s) from systemd");
+    } else {
+        server = server.bind(&bind)?;
+        log!("INFO: Listening on {bind}");
+    }
+
+    let _ = listening.listening.set(server.addrs());
+
+    server.run().await
+}
+
+fn routes(app: &mut web::ServiceConfig) {
+    let unsupported_methods = [Method::CONNECT, Method::TRACE];
+
+    // `.*` is allowed to cross slashes, so this matches every path.  It's
+    // only for matching: the path actix extracts is percent-decoded, so
+    // `proxy` sends on the request's own instead
+    for method in SUPPORTED_METHODS {
+        app.route("/{_:.*}", web::method(method).to(handler));
+    }
+
+    for method in unsupported_methods {
+        app.route("/{_:.*}", web::method(method).to(unsupported_handler));
+    }
 
-        for method in unsupported_methods {
-            app = app.route("{_:.*}", web::method(method).to(unsupported_handler));
+    // Every path matches, so only other methods (`PATCH`, made-up ones) end
+    // up here
+    app.default_service(web::to(unsupported_handler));
+}
+
+/// Checks which of the Docker daemons can be reached before we start taking
+/// requests, giving them all `limit` to answer.  Returns how many can be.
+async fn ping_docker_hosts(hosts: &[DockerHost], limit: Duration) -> usize {
+    let pings = join_all(hosts.iter().map(|host| async move {
+        match tokio::time::timeout(limit, host.docker.ping()).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(err)) => {
+                log!("WARN: Can't reach Docker host {}: {}", host.name, err);
+                false
+            }
+            Err(_) => {
+                log!(
+                    "WARN: Docker host {} didn't answer within {}ms",
+                    host.name,
+                    limit.as_millis()
+                );
+                false
+            }
         }
+    }))
+    .await;
 
-        app
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+    pings.into_iter().filter(|&reachable| reachable).count()
 }
 
-fn die<T: Debug>(reason: T) -> HttpResponse {
-    eprintln!("ERROR: {:?}", reason);
-    HttpResponse::InternalServerError()
-        .body(format!("Internal Server Error (dumbrouter/{VERSION})"))
+/// Fills the container cache before we start taking requests, but gives up
+/// after `DUMBROUTER_WARMUP_TIMEOUT_MS` rather than waiting on Docker forever.
+async fn warm_cache(data: &AppData) {
+    let deadline = data.config.warmup_timeout;
+
+    match tokio::time::timeout(deadline, data.discovery.refresh()).await {
+        Ok(Ok(count)) => log!("INFO: Found {count} container(s)"),
+        Ok(Err(err)) => log!(
+            "ERROR: Couldn't list containers ({err}), starting DEGRADED with an empty cache"
+        ),
+        Err(_) => log!(
+            "ERROR: Docker didn't list containers within {}ms, starting DEGRADED with an empty cache",
+            deadline.as_millis()
+        ),
+    }
 }
 
 async fn handler(
     conn: ConnectionInfo,
     req: HttpRequest,
-    body: Option<web::Bytes>,
+    payload: web::Payload,
     data: web::Data<AppData>,
-    path: web::Path<String>,
 ) -> impl Responder {
-    let host = conn.host().to_string();
+    let full_host = conn.host().to_string();
 
-    // Remove port - dumbrouter is port-agnostic
-    let host = host.split(":").collect::<Vec<_>>()[0];
-    let host_parts = host.split(".").map(String::from).collect::<Vec<_>>();
-    let service = service_from_host_parts(host_parts);
+    // Remove port - dumbrouter is port-agnostic (unless told otherwise).  A
+    // fully-qualified `example.com.` is the same host as `example.com`
+    let host = without_port(&full_host);
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let mut ctx = RequestContext::new(&req, host);
 
-    let dest_host = dest_host_for_service(&data.docker, &service).await;
+    if data.tracer.is_some() || data.config.propagate_traces {
+        ctx.trace = Some(TraceContext::new(&req));
+    }
+    let request_bytes = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+
+    let debug = data.config.debug_headers
+        || data.config.debug_token.as_ref().is_some_and(|token| {
+            let sent = req
+                .headers()
+                .get(DEBUG_HEADER)
+                .and_then(|v| v.to_str().ok());
+            token_matches(sent, token)
+        });
+
+    let mut response = proxy(&mut ctx, &full_host, req, payload, &data).await;
+
+    if debug {
+        add_debug_headers(&ctx, response.headers_mut());
+    }
 
-    if let Err(err) = dest_host {
-        return die(err);
+    // HTTP/1.0 has no chunked encoding, but actix would use it anyway for
+    // streamed bodies.  Those end when the connection does instead
+    if ctx.version == Version::HTTP_10 {
+        let head = response.head_mut();
+        head.no_chunking(true);
+        head.set_connection_type(ConnectionType::Close);
     }
 
-    let dest_host = dest_host.unwrap();
+    data.metrics.record(&ctx, response.status());
 
-    if dest_host.is_none() {
-        return HttpResponse::InternalServerError().body(format!(
-            "No backend found for service {service}.  (dumbrouter/{VERSION})"
-        ));
+    // Streamed bodies aren't counted, they're still on their way.  HEAD
+    // responses only say how big their body would be
+    let response_bytes = match response.body().size() {
+        _ if ctx.method == Method::HEAD => Some(0),
+        BodySize::Sized(bytes) => Some(bytes),
+        _ => None,
+    };
+
+    if let (Some(tracer), Some(trace)) = (&data.tracer, &ctx.trace) {
+        tracer.finish(
+            &ctx,
+            trace,
+            response.status(),
+            request_bytes,
+            response_bytes,
+        );
+    }
+
+    log::access(&ctx, response.status(), response_bytes);
+
+    if let Some(threshold) = data.config.slow_threshold {
+        let elapsed = ctx.started.elapsed();
+
+        if elapsed > threshold {
+            ctx.log(
+                "WARN",
+                format!("Slow request, took {}ms", elapsed.as_millis()),
+            );
+        }
     }
 
-    let dest_host = dest_host.unwrap();
+    response
+}
+
+async fn proxy(
+    ctx: &mut RequestContext,
+    full_host: &str,
+    req: HttpRequest,
+    payload: web::Payload,
+    data: &AppData,
+) -> HttpResponse {
+    let host = ctx.host.clone();
+    let host = host.as_str();
+    let client_socket = ClientSocket::of(&req);
 
-    let mut header_map = HeaderMap::new();
+    if data.config.metrics && req.path() == METRICS_PATH {
+        return HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(data.metrics.render(data.discovery.snapshot_age()));
+    }
 
-    // HACK: actix_http::header::map::HeaderMap and reqwest::header::HeaderMap
-    // are BOTH actually http::header::map::HeaderMap.  Thanks to re-exports
-    // and similar hacks (and quite possibly a lack of Rust knowledge on my
-    // part) we can't use them interchangeably.
-    for (k, v) in req.headers() {
-        header_map.insert(k, v.clone());
+    if let Some(token) = &data.config.reload_token {
+        if req.path() == RELOAD_PATH {
+            return reload(&req, token, data).await;
+        }
     }
 
-    let url = format!("http://{}/{}", dest_host, path.into_inner());
+    if let Some(dir) = &data.config.acme_dir {
+        if let Some(token) = req.path().strip_prefix(ACME_CHALLENGE_PREFIX) {
+            return acme_challenge(dir, token).await;
+        }
+    }
 
-    let mut builder = data
-        .http_client
-        .request(req.method().clone(), url)
-        .headers(header_map);
+    // Without one, actix makes up a host from the address it's bound to
+    if !req.headers().contains_key(HOST) && req.uri().authority().is_none() {
+        return ctx.missing_host();
+    }
 
-    if let Some(body) = body {
-        builder = builder.body(body);
+    // A Host that isn't even text is replaced by actix with the address it's
+    // bound to, so it's caught here rather than by `is_valid_host`
+    let unreadable = req.headers().get(HOST).is_some_and(|h| h.to_str().is_err());
+    if unreadable || !is_valid_host(full_host) {
+        return ctx.bad_host();
     }
 
-    let res = builder.send().await;
+    if data
+        .config
+        .self_hosts
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(host))
+    {
+        // Only on the router's own hosts, so that backends keep theirs (and
+        // how things are routed isn't shown to everyone)
+        if req.path() == READY_PATH {
+            return if data.discovery.ready() {
+                HttpResponse::Ok().body("ready")
+            } else {
+                HttpResponse::ServiceUnavailable().body("not ready")
+            };
+        }
 
-    if let Err(err) = res {
-        return die(err);
+        if data.config.admin && req.path() == RESOLVE_PATH {
+            return resolve(&req, data).await;
+        }
+
+        if data.config.admin && req.path() == CONFIG_PATH {
+            return HttpResponse::Ok().json(data.config.as_json());
+        }
+
+        return ctx.self_info();
     }
 
-    let res = res.unwrap();
+    let target = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
 
-    let mut resp_builder = HttpResponse::build(res.status());
+    let local_port = req.app_config().local_addr().port();
+    let route = route(&data.config, host, full_host, local_port);
 
-    for header in res.headers() {
-        resp_builder.append_header(header);
+    if let Some(redirect) = data.config.redirect_for(&route.rule_host) {
+        return ctx.redirect(&redirect, target);
     }
 
-    let body = res.bytes().await;
+    let service = route.service;
+    ctx.service = Some(service.clone());
+
+    let discovery_started = Instant::now();
+    let lookup = dest_host_for_service(
+        &data.discovery,
+        &data.config,
+        host,
+        req.headers(),
+        client_ip(&req, &data.config),
+        &service,
+        None,
+    );
+    ctx.discovery_time = Some(discovery_started.elapsed());
 
-    if let Err(err) = body {
-        return die(err);
+    match lookup {
+        // Docker hasn't answered (in time) since startup
+        Err(DiscoveryError::NotListed) => return ctx.docker_unavailable(),
+        Err(err) => return ctx.die(err),
+        Ok(_) => {}
     }
 
-    resp_builder.body(body.unwrap())
-}
+    let backend = match lookup.unwrap() {
+        Lookup::Found(backend) => backend,
+        Lookup::Down => {
+            ctx.service_known = true;
+            return ctx.service_down();
+        }
+        Lookup::Missing if data.config.is_noise(req.path()) => {
+            return ctx.noise(data.config.noise_status)
+        }
+        Lookup::Missing => {
+            return ctx.no_backend(
+                data.config.not_found_status,
+                data.config.not_found_page.as_deref(),
+                data.config.hide_service_names,
+            )
+        }
+        Lookup::AliasCycle(chain) => {
+            return ctx.die(format!("Alias cycle: {}", chain.join(" -> ")))
+        }
+        Lookup::Ambiguous(services) => return ctx.ambiguous(&services),
+        Lookup::Maintenance => {
+            ctx.service_known = true;
+            return ctx.maintenance(data.config.maintenance_page.as_deref());
+        }
+        Lookup::CircuitOpen => {
+            ctx.service_known = true;
+            return ctx.circuit_open(
+                data.config.circuit_open_status,
+                data.config.circuit_open_body.as_deref(),
+            );
+        }
+    };
 
-fn service_from_host_parts(parts: Vec<String>) -> String {
-    let root = "_root".to_string();
+    if let Some(to) = backend.label(REDIRECT_LABEL) {
+        ctx.service_known = true;
 
-    let len = parts.len();
-    match len {
-        // localhost in localhost
-        1 => parts[0].to_string(),
-        // _root for example.com
-        2 => root,
-        // a.b.c.d in a.b.c.d.example.com
-        _ => {
-            let sub = parts.split_at(len - 2).0.join(".");
+        return match Redirect::new(to, backend.label(REDIRECT_STATUS_LABEL)) {
+            Ok(redirect) => ctx.redirect(&redirect, target),
+            Err(err) => ctx.bad_gateway(format!(
+                "Container {} has a bad redirect: {err}",
+                backend.name
+            )),
+        };
+    }
 
-            if sub == "www" {
-                root
-            } else {
-                sub
-            }
-        },
+    let authorization = req.headers().get(AUTHORIZATION);
+    let subject = match data
+        .jwt
+        .check(&backend, authorization.and_then(|v| v.to_str().ok()))
+        .await
+    {
+        Ok(subject) => subject,
+        Err(why) => {
+            ctx.service_known = true;
+            return ctx.unauthorized(&why);
+        }
+    };
+
+    let max_body = match backend.label(MAX_BODY_SIZE_LABEL).map(parse_size) {
+        None => data.config.max_body,
+        Some(Some(0)) => None,
+        Some(Some(max)) => Some(max),
+        Some(None) => {
+            ctx.service_known = true;
+            return ctx.bad_gateway(format!(
+                "Container {} has a bad max body size",
+                backend.name
+            ));
+        }
+    };
+
+    // Turned away before reading any of it.  Bodies of unknown length are
+    // cut off once they go over instead
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if let Some(max) = max_body.filter(|&max| content_length.is_some_and(|len| len > max)) {
+        ctx.service_known = true;
+        return ctx.payload_too_large(max);
     }
-}
 
-async fn dest_host_for_service(
-    docker: &Docker,
-    service: &String,
-) -> Result<Option<String>, bollard::errors::Error> {
-    let mut filters = HashMap::new();
-    filters.insert("status", vec!["running"]);
+    let mut skip = CLIENT_CERT_HEADERS.to_vec();
+    skip.extend([DEBUG_HEADER, "content-length", "transfer-encoding"]);
+    let mut budget = HeaderBudget::new(&data.config);
+    let mut header_map = upstream_headers(req.headers(), &skip, &mut budget);
+    if budget.dropped > 0 {
+        ctx.log(
+            "WARN",
+            format!("Left out {} oversized request header(s)", budget.dropped),
+        );
+        ctx.dropped_headers += budget.dropped;
+    }
 
-    Ok(docker
-        .list_containers(Some(ListContainersOptions {
-            all: true,
-            filters,
-            ..Default::default()
-        }))
-        .await?
-        .iter()
-        .filter_map(|c| {
-            let names = &c.names;
-            if names.is_none() {
-                return None;
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    let trusted = peer.is_some_and(|ip| data.config.trusts(ip));
+    set_forwarded(&mut header_map, peer, full_host, trusted);
+
+    // The copy is of what the client sent, not of what this backend's labels
+    // and auth make of it
+    let mirror = mirror::sampled(&backend).map(|service| (service, header_map.clone()));
+
+    // Asked before the labels' headers are added, which the auth service has
+    // no business seeing
+    if let Some(endpoint) = ForwardAuth::endpoint(&backend, &data.config) {
+        for name in &data.config.forward_auth_headers {
+            header_map.remove(name.as_str());
+        }
+
+        let uri = req.uri().path_and_query().map_or("/", |uri| uri.as_str());
+        let verdict = data
+            .forward_auth
+            .check(
+                endpoint,
+                req.method(),
+                full_host,
+                uri,
+                &header_map,
+                &data.config,
+            )
+            .await;
+
+        match verdict {
+            Ok(Verdict::Allow(headers)) => {
+                for (name, value) in headers {
+                    header_map.insert(name, value);
+                }
+            }
+            Ok(Verdict::Deny(answer)) => {
+                ctx.service_known = true;
+                return answer;
             }
+            // Nothing gets through without a yes
+            Err(err) => {
+                ctx.service_known = true;
+                return ctx.bad_gateway(format!("Forward auth failed: {err}"));
+            }
+        }
+    }
+
+    backend_headers(ctx, &data.config, &backend, host, &mut header_map);
 
-            let names = names.as_ref().unwrap();
-            if names.len() != 1 {
-                return None;
+    // Only ever what we checked, never what the client (or a label) says
+    if let Some(subject) = subject {
+        header_map.remove(SUBJECT_HEADER);
+
+        if let Some(subject) = subject.and_then(|sub| HeaderValue::from_str(&sub).ok()) {
+            header_map.insert(SUBJECT_HEADER, subject);
+        }
+    }
+
+    if let Ok(via) = HeaderValue::from_str(&data.via) {
+        header_map.append(VIA, via);
+    }
+
+    if let Some(trace) = &ctx.trace {
+        if let Ok(traceparent) = HeaderValue::from_str(&trace.traceparent()) {
+            header_map.insert("traceparent", traceparent);
+        }
+    }
+
+    ctx.service_known = true;
+    let _in_flight = data.metrics.in_flight(&service);
+
+    ctx.backend = Some(format!(
+        "{}@{} on {}",
+        backend.name, backend.addr, backend.docker_host
+    ));
+    ctx.backend_addr = Some(format!(
+        "{}@{}",
+        backend.name.trim_start_matches('/'),
+        backend.addr
+    ));
+
+    // A container publishing our own port (say, with `network_mode: host`)
+    // would have us proxying to ourselves forever.  That's caught right away
+    // when the address is obviously ours, and by `Via` on the way back round
+    // otherwise (or when the loop goes through other proxies)
+    let listening = data.listening.get().map_or(&[][..], Vec::as_slice);
+    if is_own_addr(&backend.addr, listening) {
+        return ctx.loop_detected("The backend's address is dumbrouter's own");
+    }
+
+    let via = req.headers().get_all(VIA);
+    if via
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|hop| hop.trim() == data.via)
+    {
+        return ctx.loop_detected("The request has been through this dumbrouter before");
+    }
+
+    let scheme = match backend.label(SCHEME_LABEL) {
+        Some("https") => "https",
+        _ => "http",
+    };
+
+    let client = match data
+        .clients
+        .for_backend(&backend, data.discovery.generation())
+    {
+        Ok(client) => client,
+        Err(err) => return ctx.bad_gateway(err),
+    };
+
+    // Exactly as the client sent it, so `%2F` stays an escaped slash rather
+    // than becoming a real one (and `%3F` doesn't start a query), and `/api`
+    // and `/api/` stay different, and `//` isn't collapsed.  Only an
+    // absolute-form target with no path (`GET http://host`) has none of its
+    // own, and that means `/`.
+    //
+    // The exception is `.` and `..` segments (`%2e` included), which reqwest's
+    // URL type always resolves, so `/a/../b` is sent as `/b`.  Sending them
+    // as-is would take a second HTTP client just for those requests.  We
+    // don't serve files, so either way what they mean is up to the backend
+    // (which sees no more than a client could have asked it for directly)
+    let target = rewrite::apply(&backend, &mut header_map, target);
+    let url = format!("{}://{}{}", scheme, backend.addr, target);
+
+    let head = req.method() == Method::HEAD;
+    let head_as_get = head && backend.label(HEAD_AS_GET_LABEL) == Some("true");
+    let method = if head_as_get {
+        Method::GET
+    } else {
+        req.method().clone()
+    };
+
+    let mut builder = client.request(method, url).headers(header_map);
+
+    // Small bodies are cheaper to send in one go, big (or chunked) ones are
+    // streamed so that they never have to fit in memory
+    let chunked = req.headers().contains_key(TRANSFER_ENCODING);
+
+    // The client's framing headers aren't passed on: reqwest frames what it
+    // sends itself.  A streamed body of known length still gets its
+    // Content-Length, or it would go out chunked.  Transfer-Encoding wins
+    // over Content-Length when a client sends both
+    let body_timeout = data.config.client_body_timeout;
+    let mut buffered = None;
+    match (content_length, chunked) {
+        (None, false) => buffered = Some(web::Bytes::new()),
+        (Some(len), false) if len <= data.config.stream_threshold => {
+            match body::buffer(payload, body_timeout).await {
+                Ok(body) => {
+                    buffered = Some(body.clone());
+                    builder = builder.body(body);
+                }
+                Err(err) if body::is_timed_out(&err) => return ctx.request_timeout(),
+                Err(err) => return ctx.bad_request(err),
             }
+        }
+        (Some(len), false) => {
+            builder = builder.header(CONTENT_LENGTH, len).body(body::stream(
+                payload,
+                body_timeout,
+                max_body,
+            ))
+        }
+        (_, true) => builder = builder.body(body::stream(payload, body_timeout, max_body)),
+    }
 
-            let name = names.get(0).unwrap();
+    // Stop waiting for the backend (which drops the connection to it) as soon
+    // as the client gives up
+    let upstream_started = Instant::now();
+    let read_timeout = data.config.read_timeout;
+    let mut retries = 0;
 
-            let start_base = format!("/http-{}", service);
-            let start_prod = format!("/http-prod-{}", service);
+    let res = loop {
+        // Streamed bodies are gone once sent, so they can't be cloned (or
+        // retried)
+        let again = builder.try_clone();
+        let replayable = again.is_some();
+        let res = unless_closed(client_socket.as_ref(), within(read_timeout, builder.send())).await;
 
-            if name.len() < start_base.len()
-                || name.get(..start_base.len()).unwrap().to_string() != start_base
-                    && (name.len() < start_prod.len()
-                        || name.get(..start_prod.len()).unwrap().to_string() != start_prod)
+        match (&res, again) {
+            (Some(Some(Err(err))), Some(again))
+                if retries < data.config.retries
+                    && may_retry(req.method(), err, &data.config.retry_methods, replayable) =>
             {
-                return None;
+                retries += 1;
+                ctx.log("WARN", format!("Retrying ({retries}) after: {err}"));
+                builder = again;
             }
+            _ => break res,
+        }
+    };
+    ctx.upstream_time = Some(upstream_started.elapsed());
+
+    // Only once the real request is out of the way, so that the copy can't
+    // get there first.  Only a body that's been read in whole can be sent
+    // twice
+    if let (Some((service, headers)), Some(body)) = (mirror, buffered) {
+        mirror_to(ctx, data, &req, host, service, headers, body);
+    }
+
+    let Some(res) = res else {
+        return ctx.client_gone();
+    };
+
+    let Some(res) = res else {
+        return ctx.gateway_timeout();
+    };
+
+    if let Err(err) = res {
+        // A streamed body the client was too slow to send
+        if body::is_timed_out(&err) {
+            return ctx.request_timeout();
+        }
+
+        if let Some(max) = max_body.filter(|_| body::is_too_large(&err)) {
+            return ctx.payload_too_large(max);
+        }
+
+        if err.is_connect() {
+            data.discovery.eject(&backend);
+        }
+
+        // Most likely a certificate the backend's TLS settings don't accept
+        if scheme == "https" && err.is_connect() {
+            return ctx.bad_gateway(err);
+        }
+
+        return ctx.die(err);
+    }
+
+    let res = res.unwrap();
+
+    let mut resp_builder = HttpResponse::build(backend.remap_status(res.status()));
+
+    // Without a Content-Length (i.e. a chunked response) the backend may
+    // still be producing the body, so relay it as it arrives and let actix
+    // do the chunked framing itself.  Server-sent events never end, so they
+    // always are
+    let event_stream = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/event-stream"));
+    let streaming = event_stream || res.content_length().is_none();
+
+    let cookie_domain = backend.label(COOKIE_DOMAIN_LABEL);
+    let connection = connection_headers(res.headers().get_all(CONNECTION).iter());
+    let mut budget = HeaderBudget::new(&data.config);
+
+    for (k, v) in res.headers() {
+        // actix frames the response itself, from the body it's given
+        if k == TRANSFER_ENCODING || k == CONTENT_LENGTH {
+            continue;
+        }
 
-            let ports = &c.ports;
-            if ports.is_none() {
-                eprintln!("WARN: Container {} is http, but has no port!", name);
-                return None;
+        if is_hop_by_hop(k.as_str(), &connection) {
+            continue;
+        }
+
+        if !budget.admit(k.as_str(), v.as_bytes()) {
+            continue;
+        }
+
+        match cookie_domain {
+            Some(domain) if k == SET_COOKIE => {
+                resp_builder.append_header((k, rewrite_cookie_domain(v, domain)))
             }
+            _ => resp_builder.append_header((k, v)),
+        };
+    }
+
+    if budget.dropped > 0 {
+        ctx.log(
+            "WARN",
+            format!("Left out {} oversized response header(s)", budget.dropped),
+        );
+        ctx.dropped_headers += budget.dropped;
+    }
+
+    for header in label_headers(&backend, RESP_HEADER_PREFIX) {
+        resp_builder.insert_header(header);
+    }
+
+    // HEAD responses never have a body, so there's nothing to wait for (with
+    // `head_as_get`, the GET's headers are what HEAD should have said, and
+    // its body is thrown away unread).  actix would send `Content-Length: 0`
+    // for an empty body, so the backend's length is kept by "streaming"
+    // nothing instead
+    if head {
+        let length = res
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+
+        return match length {
+            Some(len) => resp_builder
+                .no_chunking(len)
+                .streaming(stream::empty::<Result<web::Bytes, reqwest::Error>>()),
+            None => resp_builder.body(actix_web::body::None::new()),
+        };
+    }
+
+    // These never have a body either, whatever the backend's headers say
+    let status = res.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return resp_builder.body(actix_web::body::None::new());
+    }
+
+    let upstream_length = res.content_length();
+
+    // Whether it's compressed or not depends on the client from here on, so
+    // caches have to be told
+    let compression = data
+        .config
+        .compression
+        .as_ref()
+        .filter(|c| compress::compressible(status, res.headers(), upstream_length, c));
 
-            let ports = ports.as_ref().unwrap();
-            if ports.len() < 1 {
-                eprintln!("WARN: Container {} is http, but has no port!", name);
-                return None;
+    if compression.is_some() && !compress::varies_by_encoding(res.headers()) {
+        resp_builder.append_header((VARY, "Accept-Encoding"));
+    }
+
+    let accept_encoding = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let compression = compression.and_then(|c| {
+        let encoding = compress::negotiate(accept_encoding, &c.encodings)?;
+        Some((encoding, c))
+    });
+
+    if let Some((encoding, _)) = compression {
+        resp_builder.insert_header((CONTENT_ENCODING, encoding.name()));
+
+        // It's not the same bytes any more, just the same thing
+        let etag = res.headers().get(ETAG).and_then(|v| v.to_str().ok());
+        if let Some(etag) = etag.filter(|etag| !etag.starts_with("W/")) {
+            resp_builder.insert_header((ETAG, format!("W/{etag}")));
+        }
+    }
+
+    let body = body::read_timeout(res.bytes_stream(), read_timeout, ctx.id.clone());
+
+    // The backend can't send more than its Content-Length, so that's all
+    // there is to check when there is one
+    let max_response = data.config.max_response;
+    if let Some(size) = upstream_length.filter(|&size| max_response.is_some_and(|max| size > max)) {
+        return ctx.response_too_large(size);
+    }
+
+    // The headers are sent on before the body, so a backend that stalls (or
+    // goes over `DUMBROUTER_MAX_RESP_BYTES`) mid-stream just gets the
+    // response cut short
+    if streaming {
+        let mut body = body.boxed_local();
+
+        if let Some(max) = max_response {
+            body = body::capped(body, max, ctx.id.clone()).boxed_local();
+        }
+
+        if let Some((encoding, c)) = compression {
+            body = compress::stream(body, encoding, c).boxed_local();
+        }
+
+        return resp_builder.streaming(body);
+    }
+
+    let size = upstream_length.unwrap_or_default();
+    let Some(body) = unless_closed(client_socket.as_ref(), body::collect(body)).await else {
+        return ctx.client_gone();
+    };
+
+    // Anything else going wrong is the backend's framing (the client going
+    // away is taken care of above), so it's a bad gateway
+    match body {
+        Ok(body) => match compression {
+            Some((encoding, c)) => match compress::all(&body, encoding, c) {
+                Ok(body) => resp_builder.body(body),
+                Err(err) => ctx.die(format!("Can't compress response: {err}")),
+            },
+            None => resp_builder.body(body),
+        },
+        Err(err) if err.error.kind() == io::ErrorKind::TimedOut => ctx.gateway_timeout(),
+        Err(err) => ctx.bad_gateway(format!(
+            "Backend sent {} of the {size} bytes it said it would: {}",
+            err.received, err.error
+        )),
+    }
+}
+
+/// What `backend` (or the settings its labels stand in for) changes about
+/// a request's `headers` on the way to it: the `User-Agent` tag, `Host`,
+/// `Accept-Encoding` and `dumbrouter.req_header.*`.  `host` is the request's,
+/// without the port.
+fn backend_headers(
+    ctx: &RequestContext,
+    config: &Config,
+    backend: &Backend,
+    host: &str,
+    headers: &mut HeaderMap,
+) {
+    if let Some(tag) = &config.ua_tag {
+        let ua = match headers.get(USER_AGENT).and_then(|v| v.to_str().ok()) {
+            Some(ua) => format!("{ua} {tag}"),
+            None => tag.clone(),
+        };
+
+        if let Ok(ua) = HeaderValue::from_str(&ua) {
+            headers.insert(USER_AGENT, ua);
+        }
+    }
+
+    // Routing never looks at the port, but the backend may want it (or not)
+    let forward_host = match backend.label(FORWARD_HOST_LABEL) {
+        Some(value) => ForwardHost::parse(value),
+        None => config.forward_host.clone(),
+    };
+    let forwarded = match &forward_host {
+        ForwardHost::Original => None,
+        ForwardHost::Stripped => Some(host),
+        ForwardHost::Set(host) => Some(host.as_str()),
+    };
+    if let Some(forwarded) = forwarded {
+        match HeaderValue::from_str(forwarded) {
+            Ok(forwarded) => {
+                headers.insert(HOST, forwarded);
             }
+            Err(_) => ctx.log(
+                "WARN",
+                format!("Bad forwarded Host {forwarded:?}, sending the client's"),
+            ),
+        }
+    }
 
-            let ports = ports
-                .iter()
-                .filter(|p| p.ip.is_some() && p.public_port.is_some())
-                .collect::<Vec<_>>();
-
-            if ports.len() < 1 {
-                eprintln!(
-                    "WARN: Container {} needs 1 eligible port, but has {}!",
-                    name,
-                    ports.len()
-                );
-                return None;
+    let accept_encoding = backend
+        .label(UPSTREAM_ACCEPT_ENCODING_LABEL)
+        .or(config.upstream_accept_encoding.as_deref());
+    match accept_encoding {
+        None | Some("client") => {}
+        Some("remove") => {
+            headers.remove(ACCEPT_ENCODING);
+        }
+        Some(value) => match HeaderValue::from_str(value) {
+            Ok(value) => {
+                headers.insert(ACCEPT_ENCODING, value);
             }
+            Err(_) => ctx.log(
+                "WARN",
+                format!("Bad upstream Accept-Encoding {value:?}, sending the client's"),
+            ),
+        },
+    }
 
-            let port = ports.get(0).unwrap();
+    for (name, value) in label_headers(backend, REQ_HEADER_PREFIX) {
+        headers.insert(name, value);
+    }
+}
 
-            Some(format!(
-                "{}:{}",
-                env::var("LOCALHOST_IP").unwrap_or("host.docker.internal".to_string()),
-                port.public_port.as_ref().unwrap()
-            ))
-        })
-        .choose(&mut rand::thread_rng()))
+/// `size` in bytes: a number, maybe with `k`, `m` or `g` (any case) after it
+/// for KiB, MiB or GiB.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_ascii_lowercase();
+    let (number, unit) = match size.strip_suffix(['k', 'm', 'g']) {
+        Some(number) => (number, &size[number.len()..]),
+        None => (size.as_str(), ""),
+    };
+
+    let multiplier = match unit {
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => 1,
+    };
+
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Where `req` really came from: its peer, or the `X-Real-IP` a trusted
+/// proxy says.
+fn client_ip(req: &HttpRequest, config: &Config) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+
+    if !config.trusts(peer) {
+        return Some(peer);
+    }
+
+    let real = req
+        .headers()
+        .get("x-real-ip")
+        .and_then(|v| v.to_str().ok()?.trim().parse().ok());
+
+    Some(real.unwrap_or(peer))
+}
+
+/// Whether a request that failed with `err` can be sent again without risk
+/// of it happening twice.  A request that couldn't even connect never reached
+/// the backend, so any method can be.  Otherwise the backend may well have
+/// acted on it, so only `methods` (the idempotent ones, by default) are.
+/// Requests that aren't `replayable` (their bodies were streamed, and are
+/// gone) or whose bodies the client was too slow with aren't retried at all.
+fn may_retry(method: &Method, err: &reqwest::Error, methods: &[Method], replayable: bool) -> bool {
+    if !replayable || body::is_timed_out(err) {
+        return false;
+    }
+
+    err.is_connect() || methods.contains(method)
+}
+
+/// Sends a copy of `req` to a backend of `service` as well, for
+/// `dumbrouter.mirror`, with `headers` (the client's, as they'd be sent to any
+/// backend) and `body`.  It's made up for that backend like the real request
+/// is for its own, but without what only the real one was vouched for.
+fn mirror_to(
+    ctx: &RequestContext,
+    data: &AppData,
+    req: &HttpRequest,
+    host: &str,
+    service: &str,
+    mut headers: HeaderMap,
+    body: web::Bytes,
+) {
+    // Not by host, or a `dumbrouter.hosts` claim would just be the same
+    // container again
+    let lookup = dest_host_for_service(
+        &data.discovery,
+        &data.config,
+        "",
+        req.headers(),
+        client_ip(req, &data.config),
+        service,
+        None,
+    );
+    let Ok(Lookup::Found(backend)) = lookup else {
+        ctx.log("WARN", format!("No backend to mirror to for {service}"));
+        return;
+    };
+
+    let client = match data
+        .clients
+        .for_backend(&backend, data.discovery.generation())
+    {
+        Ok(client) => client,
+        Err(err) => {
+            ctx.log("WARN", format!("Can't mirror to {}: {err}", backend.name));
+            return;
+        }
+    };
+
+    // Neither forward auth nor a JWT check looked at the copy
+    for name in &data.config.forward_auth_headers {
+        headers.remove(name.as_str());
+    }
+    headers.remove(SUBJECT_HEADER);
+
+    backend_headers(ctx, &data.config, &backend, host, &mut headers);
+
+    if let Ok(via) = HeaderValue::from_str(&data.via) {
+        headers.append(VIA, via);
+    }
+
+    let target = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |target| target.as_str());
+    let target = rewrite::apply(&backend, &mut headers, target);
+
+    let scheme = match backend.label(SCHEME_LABEL) {
+        Some("https") => "https",
+        _ => "http",
+    };
+
+    let method = match req.method() {
+        &Method::HEAD if backend.label(HEAD_AS_GET_LABEL) == Some("true") => Method::GET,
+        method => method.clone(),
+    };
+
+    let request = client
+        .request(method, format!("{scheme}://{}{target}", backend.addr))
+        .headers(headers)
+        .body(body)
+        .build();
+
+    match request {
+        Ok(request) => data.mirror.send(
+            client,
+            request,
+            data.config.read_timeout,
+            ctx.id.clone(),
+            service.to_string(),
+        ),
+        Err(err) => ctx.log("WARN", format!("Can't mirror to {}: {err}", backend.name)),
+    }
 }
 
-async fn unsupported_handler() -> impl Responder {
-    HttpResponse::NotImplemented().body(format!(
-        "This method is not supported.  (dumbrouter/{VERSION})"
-    ))
+/// `future`'s output, or `None` if it takes longer than `limit`.
+async fn within<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Reloads what can be reloaded without a restart: the config file's rules,
+/// and the container listing.  Needs `token` in `X-Dumbrouter-Reload-Token`.
+async fn reload(req: &HttpRequest, token: &str, data: &AppData) -> HttpResponse {
+    if req.method() != Method::POST {
+        return HttpResponse::MethodNotAllowed()
+            .insert_header(("Allow", "POST"))
+            .json(json!({ "error": "Use POST" }));
+    }
+
+    let sent = req
+        .headers()
+        .get(RELOAD_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if !token_matches(sent, token) {
+        return HttpResponse::Forbidden().json(json!({ "error": "Bad reload token" }));
+    }
+
+    // The old rules stay if the file is broken
+    let rules = match data.config.reload_rules() {
+        Ok(rules) => rules,
+        Err(err) => {
+            log!("ERROR: Not reloading: {err}");
+            return HttpResponse::UnprocessableEntity().json(json!({ "error": err }));
+        }
+    };
+
+    let containers = data.discovery.refresh().await;
+    log!(
+        "INFO: Reloaded {} rule(s) and {} redirect(s)",
+        rules.host_rules.len(),
+        rules.redirect_rules.len()
+    );
+
+    let status = match containers {
+        Ok(_) => StatusCode::OK,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    HttpResponse::build(status).json(json!({
+        "config_path": data.config.config_path,
+        "host_rules": rules.host_rules.len(),
+        "redirect_rules": rules.redirect_rules.len(),
+        "containers": containers.as_ref().ok(),
+        "docker_error": containers.err().map(|err| err.to_string()),
+    }))
+}
+
+/// Whether `sent` is `token`, taking as long to say no however much of it is
+/// right, so that it can't be guessed a byte at a time.
+fn token_matches(sent: Option<&str>, token: &str) -> bool {
+    sent.is_some_and(|sent| {
+        sent.len() == token.len() && openssl::memcmp::eq(sent.as_bytes(), token.as_bytes())
+    })
+}
+
+/// Shows how a request for `?host=` would be routed, without sending one.
+async fn resolve(req: &HttpRequest, data: &AppData) -> HttpResponse {
+    let full_host = req
+        .query_string()
+        .split('&')
+        .find_map(|param| param.strip_prefix("host="))
+        .filter(|host| !host.is_empty());
+
+    let Some(full_host) = full_host else {
+        return HttpResponse::BadRequest().body(format!(
+            "Usage: {RESOLVE_PATH}?host=<host>  (dumbrouter/{VERSION})"
+        ));
+    };
+
+    // Same as `handler` and `proxy` do it
+    let host = without_port(full_host);
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let route = route(
+        &data.config,
+        host,
+        full_host,
+        req.app_config().local_addr().port(),
+    );
+
+    let mut explain = Explanation::default();
+    let lookup = dest_host_for_service(
+        &data.discovery,
+        &data.config,
+        host,
+        req.headers(),
+        client_ip(req, &data.config),
+        &route.service,
+        Some(&mut explain),
+    );
+
+    let (result, detail) = match lookup {
+        Ok(Lookup::Found(_)) => ("found", json!(null)),
+        Ok(Lookup::Down) => ("down", json!(null)),
+        Ok(Lookup::Missing) => ("missing", json!(null)),
+        Ok(Lookup::AliasCycle(chain)) => ("alias_cycle", json!(chain)),
+        Ok(Lookup::Ambiguous(services)) => ("ambiguous", json!(services)),
+        Ok(Lookup::CircuitOpen) => ("circuit_open", json!(null)),
+        Ok(Lookup::Maintenance) => ("maintenance", json!(null)),
+        Err(err) => ("error", json!(err.to_string())),
+    };
+
+    let candidates = explain
+        .candidates
+        .iter()
+        .map(|(name, skipped)| json!({ "name": name, "skipped": skipped }))
+        .collect::<Vec<_>>();
+
+    // Which of these a request gets is random, so they're all listed
+    let eligible = explain
+        .eligible
+        .iter()
+        .map(|b| {
+            json!({
+                "name": b.name,
+                "addr": b.addr,
+                "docker_host": b.docker_host,
+                "weight": b.weight,
+                "draining": b.draining,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::Ok().json(json!({
+        "host": full_host,
+        "normalized_host": host,
+        "rule_host": route.rule_host,
+        "rule": route.rule.map(|rule| json!({ "pattern": rule.pattern, "service": rule.service })),
+        "redirect": data.config.redirect_for(&route.rule_host).map(|redirect| redirect.to),
+        "service": route.service,
+        "claimed_by": explain.claimed_by,
+        "services": explain.services,
+        "candidates": candidates,
+        "eligible": eligible,
+        "variant": explain.variant,
+        "result": result,
+        "detail": detail,
+        "snapshot_age_ms": data.discovery.snapshot_age().map(|age| age.as_millis() as u64),
+    }))
+}
+
+/// Says where the request ended up, for working out which replica answered.
+fn add_debug_headers(ctx: &RequestContext, headers: &mut actix_web::http::header::HeaderMap) {
+    let debug = [
+        ("x-dumbrouter-service", &ctx.service),
+        ("x-dumbrouter-backend", &ctx.backend_addr),
+    ];
+
+    for (name, value) in debug {
+        if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+}
+
+/// Serves an ACME HTTP-01 challenge token from `dir`.
+async fn acme_challenge(dir: &str, token: &str) -> HttpResponse {
+    // Tokens are base64url, which keeps them from escaping `dir`
+    let valid = !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return HttpResponse::NotFound().finish();
+    }
+
+    match tokio::fs::read(Path::new(dir).join(token)).await {
+        Ok(contents) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(contents),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Where a host is sent, before any containers are looked at.
+struct Route {
+    /// What rules are matched against: the host, plus the port in port-aware
+    /// mode
+    rule_host: String,
+    rule: Option<HostRule>,
+    service: String,
+}
+
+/// Works out the service for a request to `host` (`full_host` being the
+/// `Host` header as sent, and `local_port` the port it came in on).
+fn route(config: &Config, host: &str, full_host: &str, local_port: u16) -> Route {
+    // In port-aware mode the port becomes part of the service (`foo@8443`),
+    // and rules are matched against `host:port`
+    let port = config.port_aware.then(|| {
+        full_host
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+            .unwrap_or(local_port)
+    });
+
+    let rule_host = match port {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+
+    let rule = config.rule_for(&rule_host);
+
+    let service = match &rule {
+        Some(rule) => rule.service.clone(),
+        None => {
+            let service = service_under_base(host, &config.base_domains).unwrap_or_else(|| {
+                let host_parts = host.split('.').map(String::from).collect::<Vec<_>>();
+                service_from_host_parts(host_parts)
+            });
+
+            match port {
+                Some(port) => format!("{service}@{port}"),
+                None => service,
+            }
+        }
+    };
+
+    Route {
+        rule_host,
+        rule,
+        service,
+    }
+}
+
+/// Whether `addr`, a backend's `host:port`, is one of the addresses we're
+/// `listening` on.  Only IPs are checked: a hostname could be anything.
+fn is_own_addr(addr: &str, listening: &[SocketAddr]) -> bool {
+    let Ok(addr) = addr.parse::<SocketAddr>() else {
+        return false;
+    };
+
+    // Listening on every address (or on loopback) includes loopback
+    let covers = |own: IpAddr| own.is_unspecified() || own.is_loopback();
+
+    listening.iter().any(|own| {
+        own.port() == addr.port()
+            && (own.ip() == addr.ip() || (addr.ip().is_loopback() && covers(own.ip())))
+    })
+}
+
+/// `full_host` (a `Host` header) without its port, if it has one.  IPv6
+/// literals keep their brackets: `[::1]` for `[::1]:8080`.  Anything after
+/// the last `:` that isn't a port is left on, for `is_valid_host` to turn
+/// away.
+fn without_port(full_host: &str) -> &str {
+    let is_port = |port: &str| port.bytes().all(|b| b.is_ascii_digit());
+
+    if full_host.starts_with('[') {
+        return match full_host.split_once(']') {
+            Some((ip, "")) => &full_host[..=ip.len()],
+            Some((ip, rest)) if rest.strip_prefix(':').is_some_and(is_port) => {
+                &full_host[..=ip.len()]
+            }
+            _ => full_host,
+        };
+    }
+
+    match full_host.rsplit_once(':') {
+        Some((host, port)) if is_port(port) => host,
+        _ => full_host,
+    }
+}
+
+/// Whether `full_host` (a `Host` header, maybe with a port) looks like a
+/// hostname or IP at all, rather than the junk bots send.
+fn is_valid_host(full_host: &str) -> bool {
+    let host = without_port(full_host);
+
+    if let Some(ip) = host.strip_prefix('[') {
+        return full_host.len() <= 255
+            && ip
+                .strip_suffix(']')
+                .is_some_and(|ip| ip.parse::<Ipv6Addr>().is_ok());
+    }
+
+    let host = host.strip_suffix('.').unwrap_or(host);
+
+    !host.is_empty()
+        && full_host.len() <= 255
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// The service for `host` if it's one of `base_domains` (longest first) or
+/// under one: `a.b` in `a.b.example.co.uk`, and `_root` for the domain itself
+/// or its `www`.
+fn service_under_base(host: &str, base_domains: &[String]) -> Option<String> {
+    let host = host.to_ascii_lowercase();
+
+    let sub = base_domains.iter().find_map(|base| {
+        if host == *base {
+            return Some("");
+        }
+
+        host.strip_suffix(base.as_str())?.strip_suffix('.')
+    })?;
+
+    Some(match sub {
+        "" | "www" => "_root".to_string(),
+        sub => sub.to_string(),
+    })
+}
+
+fn service_from_host_parts(parts: Vec<String>) -> String {
+    let root = "_root".to_string();
+
+    let len = parts.len();
+    match len {
+        // Nothing to go on, which `is_valid_host` keeps from getting here
+        0 => String::new(),
+        // localhost in localhost
+        1 => parts[0].to_string(),
+        // _root for example.com
+        2 => root,
+        // a.b.c.d in a.b.c.d.example.com
+        _ => {
+            let sub = parts.split_at(len - 2).0.join(".");
+
+            if sub == "www" {
+                root
+            } else {
+                sub
+            }
+        }
+    }
+}
+
+/// The methods dumbrouter sends on, as an `Allow` header.
+fn allowed_methods() -> String {
+    SUPPORTED_METHODS.map(|m| m.to_string()).join(", ")
+}
+
+async fn unsupported_handler(data: web::Data<AppData>) -> impl Responder {
+    // Whether it's a 405 or a 501, clients can tell what would have worked
+    HttpResponse::build(data.config.unsupported_status)
+        .insert_header(("Allow", allowed_methods()))
+        .body(format!(
+            "This method is not supported.  (dumbrouter/{VERSION})"
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use actix_web::error::PayloadError;
+    use actix_web::http::header::HeaderMap;
+    use actix_web::test::{self as actix_test, TestRequest};
+    use actix_web::web::Bytes;
+    use futures_util::Stream;
+    use serde_json::Value;
+    use std::collections::HashSet;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// dumbrouter with `config`, once it's listed its containers.
+    async fn app_data(config: Config) -> web::Data<AppData> {
+        let discovery = testing::discovery(&config).await;
+        web::Data::new(AppData::new(config, discovery))
+    }
+
+    /// Sends `req` through dumbrouter.
+    async fn call(data: &web::Data<AppData>, req: TestRequest) -> (StatusCode, HeaderMap, Bytes) {
+        let app =
+            actix_test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+        let res = actix_test::call_service(&app, req.to_request()).await;
+        let (status, headers) = (res.status(), res.headers().clone());
+
+        (status, headers, actix_test::read_body(res).await)
+    }
+
+    /// A GET for `path` on `host`.
+    fn get(host: &str, path: &str) -> TestRequest {
+        TestRequest::get().uri(path).insert_header((HOST, host))
+    }
+
+    fn ok(_: &testing::Request) -> Vec<u8> {
+        testing::response("200 OK", &[], "from the backend")
+    }
+
+    /// A backend, and a Docker with one container for it that's called `name`.
+    async fn one_backend(name: &str, labels: Value) -> (Config, testing::Requests) {
+        let (backend, requests) = testing::backend(ok).await;
+        let (url, _) =
+            testing::docker(json!([testing::container(name, backend.port(), labels)])).await;
+
+        (testing::config(&url), requests)
+    }
+
+    /// dumbrouter with `data` on a port of its own, as it's run for real.
+    fn serve(data: web::Data<AppData>) -> SocketAddr {
+        let max_connections = data.config.max_connections;
+        let mut server =
+            HttpServer::new(move || App::new().app_data(data.clone()).configure(routes))
+                .on_connect(conn::on_connect)
+                .workers(1);
+
+        if let Some(max) = max_connections {
+            server = server.max_connections(max);
+        }
+
+        let server = server.bind("127.0.0.1:0").unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        addr
+    }
+
+    #[actix_web::test]
+    async fn workers_share_one_app_data() {
+        let (url, _) = testing::docker(json!([])).await;
+        let data = app_data(testing::config(&url)).await;
+        let built = Arc::new(Mutex::new(Vec::new()));
+
+        let seen = built.clone();
+        let server = HttpServer::new(move || {
+            seen.lock().unwrap().push(data.clone().into_inner());
+            App::new().app_data(data.clone()).configure(routes)
+        })
+        .workers(2)
+        .bind("127.0.0.1:0")
+        .unwrap()
+        .run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let started = Instant::now();
+        while built.lock().unwrap().len() < 2 {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "workers never started"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handle.stop(false).await;
+
+        let built = built.lock().unwrap();
+        assert!(Arc::ptr_eq(&built[0], &built[1]));
+    }
+
+    /// Sends `request` to `addr` byte for byte, and reads all there is until
+    /// the connection's closed.
+    async fn raw(addr: SocketAddr, request: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("connection left open")
+            .unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[actix_web::test]
+    async fn ready_is_only_answered_on_self_hosts() {
+        let (mut config, requests) = one_backend("http-foo", json!({})).await;
+        config.self_hosts = vec!["router.local".to_string()];
+        let data = app_data(config).await;
+
+        let (status, _, body) = call(&data, get("router.local", READY_PATH)).await;
+        assert_eq!((status, &body[..]), (StatusCode::OK, &b"ready"[..]));
+
+        let (status, _, body) = call(&data, get("foo.example.com", READY_PATH)).await;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &b"from the backend"[..])
+        );
+        assert_eq!(requests.lock().unwrap()[0].target, READY_PATH);
+    }
+
+    #[actix_web::test]
+    async fn self_hosts_get_the_info_page() {
+        // Would be routed to, if it weren't the router's own host
+        let (mut config, requests) = one_backend("http-router", json!({})).await;
+        config.self_hosts = vec!["router.local".to_string()];
+        let data = app_data(config).await;
+
+        for host in ["router.local", "Router.Local:8080"] {
+            let (status, _, body) = call(&data, get(host, "/anything")).await;
+            assert_eq!(status, StatusCode::OK);
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.starts_with("This is dumbrouter/"), "{body}");
+        }
+        assert!(requests.lock().unwrap().is_empty());
+
+        let (status, _, body) = call(&data, get("router.example.com", "/anything")).await;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &b"from the backend"[..])
+        );
+        assert_eq!(requests.lock().unwrap()[0].target, "/anything");
+    }
+
+    #[actix_web::test]
+    async fn noise_is_only_turned_away_for_unknown_hosts() {
+        let (mut config, requests) = one_backend("http-foo", json!({})).await;
+        config.noise_paths = vec!["/favicon.ico".to_string(), "/.well-known/*".to_string()];
+        let data = app_data(config).await;
+
+        log::capture();
+        for path in ["/favicon.ico", "/.well-known/security.txt"] {
+            let (status, _, body) = call(&data, get("nothing.example.com", path)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(body.starts_with(b"Not found.  ["), "{body:?}");
+        }
+        let lines = log::captured();
+        assert!(lines.is_empty(), "{lines:?}");
+
+        let (_, _, body) = call(&data, get("nothing.example.com", "/page")).await;
+        assert!(body.starts_with(b"No backend found for service nothing"));
+
+        let (status, _, body) = call(&data, get("foo.example.com", "/favicon.ico")).await;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &b"from the backend"[..])
+        );
+        assert_eq!(requests.lock().unwrap()[0].target, "/favicon.ico");
+    }
+
+    #[actix_web::test]
+    async fn finding_backends_is_timed() {
+        let (config, _) = one_backend("http-foo", json!({})).await;
+        let data = app_data(config).await;
+
+        call(&data, get("foo.example.com", "/")).await;
+        // Turned away before anything's looked up
+        call(&data, get("bad host", "/")).await;
+
+        let metrics = data.metrics.render(None);
+        let value = |name: &str| -> f64 {
+            let line = metrics.lines().find(|line| line.starts_with(name));
+            let line = line.unwrap_or_else(|| panic!("no {name} in {metrics}"));
+            line.rsplit(' ').next().unwrap().parse().unwrap()
+        };
+        let count = "dumbrouter_discovery_duration_seconds_count";
+        assert_eq!(value(&format!("{count}{{service=\"foo\"}}")), 1.0);
+        assert!(value("dumbrouter_discovery_duration_seconds_sum{service=\"foo\"}") >= 0.0);
+        assert_eq!(value(&format!("{count}{{service=\"_unknown\"}}")), 0.0);
+    }
+
+    #[actix_web::test]
+    async fn not_ready_until_listed() {
+        let (mut config, _) = one_backend("http-foo", json!({})).await;
+        config.self_hosts = vec!["router.local".to_string()];
+        let hosts = config
+            .docker_hosts
+            .iter()
+            .map(|endpoint| DockerHost::connect(endpoint, &config).unwrap())
+            .collect();
+        let discovery = Discovery::new(hosts, &config);
+        let data = web::Data::new(AppData::new(config, discovery));
+
+        let (status, _, _) = call(&data, get("router.local", READY_PATH)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn slow_dockers_are_given_up_on() {
+        let (backend, _) = testing::backend(ok).await;
+        let listing = json!([testing::container("http-foo", backend.port(), json!({}))]);
+        let (url, _) =
+            testing::docker_with(Arc::new(Mutex::new(listing)), Duration::from_secs(5)).await;
+        let mut config = testing::config(&url);
+        config.docker_timeout = Duration::from_millis(200);
+        let hosts = config
+            .docker_hosts
+            .iter()
+            .map(|endpoint| DockerHost::connect(endpoint, &config).unwrap())
+            .collect();
+        let discovery = Discovery::new(hosts, &config);
+
+        let started = Instant::now();
+        let refreshed = discovery.refresh().await;
+        assert!(matches!(refreshed, Err(DiscoveryError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let data = web::Data::new(AppData::new(config, discovery));
+        let (status, headers, body) = call(&data, get("foo.example.com", "/")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(headers.contains_key("retry-after"));
+        assert!(body.starts_with(b"Docker isn't responding"));
+    }
+
+    #[actix_web::test]
+    async fn mirrored_requests_are_made_up_for_the_mirror() {
+        let (primary, primary_requests) = testing::backend(ok).await;
+        let (shadow, shadow_requests) = testing::backend(ok).await;
+        let (url, _) = testing::docker(json!([
+            testing::container(
+                "http-foo",
+                primary.port(),
+                json!({
+                    "dumbrouter.mirror": "shadow",
+                    "dumbrouter.req_header.X-Primary": "yes",
+                    "dumbrouter.rewrite": "set-path-prefix /primary",
+                }),
+            ),
+            testing::container(
+                "http-shadow",
+                shadow.port(),
+                json!({ "dumbrouter.req_header.X-Shadow": "yes" }),
+            ),
+        ]))
+        .await;
+        let data = app_data(testing::config(&url)).await;
+
+        let req = get("foo.example.com", "/path?q=1").insert_header(("X-Client", "hi"));
+        let (status, _, _) = call(&data, req).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let started = Instant::now();
+        while shadow_requests.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "never mirrored");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let primary = primary_requests.lock().unwrap()[0].clone();
+        let shadow = shadow_requests.lock().unwrap()[0].clone();
+        assert_eq!(primary.target, "/primary/path?q=1");
+        assert_eq!(primary.header("x-primary"), Some("yes"));
+
+        assert_eq!(shadow.target, "/path?q=1");
+        assert_eq!(shadow.header("x-shadow"), Some("yes"));
+        assert_eq!(shadow.header("x-primary"), None);
+        assert_eq!(shadow.header("x-client"), Some("hi"));
+        assert!(primary.at <= shadow.at);
+    }
+
+    #[actix_web::test]
+    async fn resolve_is_only_answered_on_self_hosts() {
+        let (mut config, requests) = one_backend("http-foo", json!({})).await;
+        config.admin = true;
+        config.self_hosts = vec!["router.local".to_string()];
+        let data = app_data(config).await;
+
+        let resolve = format!("{RESOLVE_PATH}?host=foo.example.com");
+        let (status, _, body) = call(&data, get("router.local", &resolve)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["service"], "foo");
+
+        let (_, _, body) = call(&data, get("foo.example.com", &resolve)).await;
+        assert_eq!(&body[..], b"from the backend");
+        assert_eq!(requests.lock().unwrap()[0].target, resolve);
+    }
+
+    #[actix_web::test]
+    async fn config_is_only_answered_on_self_hosts() {
+        let (mut config, _) = one_backend("http-foo", json!({})).await;
+        config.admin = true;
+        config.self_hosts = vec!["router.local".to_string()];
+        let data = app_data(config).await;
+
+        let (_, _, body) = call(&data, get("router.local", CONFIG_PATH)).await;
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["version"], VERSION);
+
+        let (_, _, body) = call(&data, get("foo.example.com", CONFIG_PATH)).await;
+        assert_eq!(&body[..], b"from the backend");
+    }
+
+    #[actix_web::test]
+    async fn user_agents_are_only_tagged_when_asked() {
+        for tag in [None, Some("edge-1")] {
+            let (mut config, requests) = one_backend("http-foo", json!({})).await;
+            config.ua_tag = tag.map(String::from);
+            let data = app_data(config).await;
+
+            let req = get("foo.example.com", "/").insert_header((USER_AGENT, "curl/8.0"));
+            call(&data, req).await;
+            call(&data, get("foo.example.com", "/")).await;
+
+            let requests = requests.lock().unwrap();
+            let (with_ua, without_ua) = match tag {
+                Some(tag) => (format!("curl/8.0 {tag}"), Some(tag)),
+                None => ("curl/8.0".to_string(), None),
+            };
+            assert_eq!(requests[0].header("user-agent"), Some(with_ua.as_str()));
+            assert_eq!(requests[1].header("user-agent"), without_ua);
+        }
+    }
+
+    #[actix_web::test]
+    async fn services_in_maintenance_get_a_503() {
+        let (backend, requests) = testing::backend(ok).await;
+        let (resting, resting_requests) = testing::backend(ok).await;
+        let maintenance = json!({ "dumbrouter.maintenance": "true" });
+        let (url, _) = testing::docker(json!([
+            testing::container("http-down", resting.port(), maintenance.clone()),
+            testing::container("http-half", resting.port(), maintenance),
+            testing::container("http-half-2", backend.port(), json!({})),
+            testing::container("http-up", backend.port(), json!({})),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        config.maintenance_page = Some("<p>{service} is resting [{id}]</p>".to_string());
+        let data = app_data(config).await;
+
+        let (status, headers, body) = call(&data, get("down.example.com", "/")).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(headers.contains_key("retry-after"));
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with("<p>down is resting ["), "{body}");
+        assert!(!body.contains("{id}"));
+
+        // Never the labeled one, however often it's asked
+        for _ in 0..10 {
+            let (status, _, _) = call(&data, get("half.example.com", "/")).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, _, body) = call(&data, get("up.example.com", "/")).await;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &b"from the backend"[..])
+        );
+
+        assert_eq!(requests.lock().unwrap().len(), 11);
+        assert!(resting_requests.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn reloads_need_the_token() {
+        let (mut config, _) = one_backend("http-foo", json!({})).await;
+        config.reload_token = Some("s3cret".to_string());
+        let data = app_data(config).await;
+
+        let reload = |token: Option<&str>| {
+            let req = TestRequest::post()
+                .uri(RELOAD_PATH)
+                .insert_header((HOST, "foo.example.com"));
+            match token {
+                Some(token) => req.insert_header((RELOAD_HEADER, token)),
+                None => req,
+            }
+        };
+
+        for token in [
+            None,
+            Some(""),
+            Some("s3cre"),
+            Some("s3cret!"),
+            Some("S3CRET"),
+        ] {
+            let (status, _, _) = call(&data, reload(token)).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{token:?}");
+        }
+
+        let (status, _, body) = call(&data, reload(Some("s3cret"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["containers"], 1);
+
+        let (status, headers, _) = call(&data, get("foo.example.com", RELOAD_PATH)).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers.get("allow").unwrap(), "POST");
+    }
+
+    #[actix_web::test]
+    async fn debug_headers_need_the_token() {
+        let (mut config, _) = one_backend("http-foo", json!({})).await;
+        config.debug_token = Some("s3cret".to_string());
+        let data = app_data(config).await;
+
+        for (token, debug) in [(None, false), (Some("nope"), false), (Some("s3cret"), true)] {
+            let mut req = get("foo.example.com", "/");
+            if let Some(token) = token {
+                req = req.insert_header((DEBUG_HEADER, token));
+            }
+
+            let (_, headers, _) = call(&data, req).await;
+            assert_eq!(
+                headers.contains_key("x-dumbrouter-service"),
+                debug,
+                "{token:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tokens_have_to_match_exactly() {
+        assert!(token_matches(Some("s3cret"), "s3cret"));
+        assert!(!token_matches(Some("s3cre"), "s3cret"));
+        assert!(!token_matches(Some("s3creT"), "s3cret"));
+        assert!(!token_matches(Some(""), "s3cret"));
+        assert!(!token_matches(None, "s3cret"));
+    }
+
+    /// What sending to a port nothing's listening on fails with.
+    async fn connect_error() -> reqwest::Error {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        reqwest::get(format!("http://{addr}/")).await.unwrap_err()
+    }
+
+    /// What a backend that takes the request and hangs up fails it with.
+    async fn error_after_sending() -> reqwest::Error {
+        let addr = testing::listen(|stream| async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            testing::read_request(&mut stream).await;
+        })
+        .await;
+
+        let err = reqwest::get(format!("http://{addr}/")).await.unwrap_err();
+        assert!(!err.is_connect());
+        err
+    }
+
+    /// What a request fails with when the client is too slow with its body.
+    async fn body_timed_out() -> reqwest::Error {
+        let addr = testing::listen(|stream| async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        })
+        .await;
+
+        let timed_out = PayloadError::Io(io::ErrorKind::TimedOut.into());
+        let body = futures_util::stream::once(async move { Err::<Bytes, _>(timed_out) });
+        let err = reqwest::Client::new()
+            .post(format!("http://{addr}/"))
+            .body(reqwest::Body::wrap_stream(body))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(body::is_timed_out(&err));
+        err
+    }
+
+    #[actix_web::test]
+    async fn only_safe_requests_are_retried() {
+        let methods = [Method::GET, Method::HEAD, Method::PUT];
+        let connect = connect_error().await;
+        let after_send = error_after_sending().await;
+        let timed_out = body_timed_out().await;
+
+        for (method, err, replayable, retried) in [
+            (Method::GET, &connect, true, true),
+            (Method::POST, &connect, true, true),
+            (Method::GET, &after_send, true, true),
+            (Method::PUT, &after_send, true, true),
+            (Method::POST, &after_send, true, false),
+            (Method::PATCH, &after_send, true, false),
+            // Bodies that were streamed can't be sent again
+            (Method::GET, &connect, false, false),
+            (Method::POST, &connect, false, false),
+            (Method::PUT, &after_send, false, false),
+            (Method::PUT, &timed_out, true, false),
+            (Method::POST, &timed_out, true, false),
+        ] {
+            assert_eq!(
+                may_retry(&method, err, &methods, replayable),
+                retried,
+                "{method} after {err} ({replayable})"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn services_with_every_backend_ejected_say_so() {
+        // Ports nothing listens on any more
+        let mut dead = vec![];
+        for _ in 0..2 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            dead.push(listener.local_addr().unwrap().port());
+        }
+        let (url, _) = testing::docker(json!([
+            testing::container("http-flaky", dead[0], json!({})),
+            testing::container("http-flaky-2", dead[1], json!({})),
+        ]))
+        .await;
+
+        for body in [None, Some("{service} is resting [{id}]")] {
+            let mut config = testing::config(&url);
+            config.eject_for = Some(Duration::from_secs(60));
+            config.circuit_open_status = StatusCode::TOO_MANY_REQUESTS;
+            config.circuit_open_body = body.map(String::from);
+            config.retries = 0;
+            let data = app_data(config).await;
+
+            // Each one fails once, and is left out after that
+            for _ in 0..2 {
+                let (status, _, _) = call(&data, get("flaky.example.com", "/")).await;
+                assert_ne!(status, StatusCode::TOO_MANY_REQUESTS);
+            }
+
+            let (status, headers, sent) = call(&data, get("flaky.example.com", "/")).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            assert!(headers.contains_key("retry-after"));
+            let sent = String::from_utf8_lossy(&sent);
+            match body {
+                Some(_) => assert!(sent.starts_with("flaky is resting ["), "{sent}"),
+                None => assert!(sent.contains("all of its backends are failing"), "{sent}"),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn down_services_are_told_apart_from_missing_ones() {
+        let mut stopped = testing::container("http-stopped", 1, json!({}));
+        stopped["State"] = json!("exited");
+        stopped["Status"] = json!("Exited (0) 5 minutes ago");
+        stopped["Ports"] = json!([]);
+        let mut unhealthy = testing::container("http-sick", 2, json!({}));
+        unhealthy["Status"] = json!("Up 5 minutes (unhealthy)");
+        let (url, _) = testing::docker(json!([stopped, unhealthy])).await;
+        let mut config = testing::config(&url);
+        config.check_health = true;
+        let data = app_data(config).await;
+
+        for host in ["stopped.example.com", "sick.example.com"] {
+            let (status, headers, body) = call(&data, get(host, "/")).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{host}");
+            assert!(headers.contains_key("retry-after"), "{host}");
+            assert!(String::from_utf8_lossy(&body).contains("temporarily down"));
+        }
+
+        let (status, headers, body) = call(&data, get("nothing.example.com", "/")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!headers.contains_key("retry-after"));
+        assert!(String::from_utf8_lossy(&body).starts_with("No backend found"));
+    }
+
+    #[actix_web::test]
+    async fn chunked_responses_are_streamed() {
+        let backend = testing::listen(|stream| async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            testing::read_request(&mut stream).await;
+
+            let stream = stream.get_mut();
+            let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(b"6\r\nhello \r\n").await;
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let _ = stream.write_all(b"5\r\nworld\r\n0\r\n\r\n").await;
+        })
+        .await;
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-foo",
+            backend.port(),
+            json!({})
+        )]))
+        .await;
+        let data = app_data(testing::config(&url)).await;
+
+        let app =
+            actix_test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+        let started = Instant::now();
+        let res = actix_test::call_service(&app, get("foo.example.com", "/").to_request()).await;
+
+        // Passed on before the backend was done with it
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(&actix_test::read_body(res).await[..], b"hello world");
+    }
+
+    #[actix_web::test]
+    async fn whole_paths_are_passed_on() {
+        let (config, requests) = one_backend("http-foo", json!({})).await;
+        let data = app_data(config).await;
+
+        let paths = ["/", "/a", "/a/", "/a/b/c/d", "/a/b/c/d?e=f/g"];
+        for path in paths {
+            let (status, _, _) = call(&data, get("foo.example.com", path)).await;
+            assert_eq!(status, StatusCode::OK, "{path}");
+        }
+
+        let targets = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.target.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(targets, paths);
+    }
+
+    #[actix_web::test]
+    async fn acme_challenges_are_answered_from_the_directory() {
+        let dir = std::env::temp_dir().join(format!("dumbrouter-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tok-EN_1"), "tok-EN_1.thumbprint").unwrap();
+
+        let (mut config, requests) = one_backend("http-foo", json!({})).await;
+        config.acme_dir = Some(dir.to_str().unwrap().to_string());
+        let data = app_data(config).await;
+
+        let challenge = format!("{ACME_CHALLENGE_PREFIX}tok-EN_1");
+        let (status, _, body) = call(&data, get("foo.example.com", &challenge)).await;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &b"tok-EN_1.thumbprint"[..])
+        );
+
+        for token in ["missing", "..%2Fsecret", ""] {
+            let path = format!("{ACME_CHALLENGE_PREFIX}{token}");
+            let (status, _, _) = call(&data, get("foo.example.com", &path)).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{token:?}");
+        }
+
+        let (status, _, body) = call(&data, get("foo.example.com", "/index.html")).await;
+        assert_eq!(
+            (status, &body[..]),
+            (StatusCode::OK, &b"from the backend"[..])
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn unsupported_methods_get_the_configured_status() {
+        for status in [StatusCode::NOT_IMPLEMENTED, StatusCode::METHOD_NOT_ALLOWED] {
+            let (mut config, requests) = one_backend("http-foo", json!({})).await;
+            if status == StatusCode::METHOD_NOT_ALLOWED {
+                config.unsupported_status = status;
+            }
+            let data = app_data(config).await;
+
+            let req = get("foo.example.com", "/").method(Method::TRACE);
+            let (got, headers, _) = call(&data, req).await;
+            assert_eq!(got, status);
+            assert!(headers.contains_key("allow"));
+            assert!(requests.lock().unwrap().is_empty());
+        }
+    }
+
+    #[actix_web::test]
+    async fn every_cookie_gets_the_labeled_domain() {
+        let (backend, _) = testing::backend(|_| {
+            let cookies = [
+                ("Set-Cookie", "sid=1; Domain=foo.internal; Path=/"),
+                ("Set-Cookie", "theme=dark; domain=foo.internal"),
+                ("Set-Cookie", "host_only=1"),
+            ];
+            testing::response("200 OK", &cookies, "")
+        })
+        .await;
+        let labels = json!({ COOKIE_DOMAIN_LABEL: "example.com" });
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-foo",
+            backend.port(),
+            labels
+        )]))
+        .await;
+        let data = app_data(testing::config(&url)).await;
+
+        let (_, headers, _) = call(&data, get("foo.example.com", "/")).await;
+        let cookies = headers
+            .get_all(SET_COOKIE)
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cookies,
+            [
+                "sid=1; Domain=example.com; Path=/",
+                "theme=dark; Domain=example.com",
+                "host_only=1",
+            ]
+        );
+    }
+
+    /// A backend that hangs up on the first request it's sent, and answers
+    /// the rest.
+    async fn hangs_up_once() -> (std::net::SocketAddr, testing::Requests) {
+        let requests = testing::Requests::default();
+        let seen = requests.clone();
+
+        let addr = testing::listen(move |stream| {
+            let seen = seen.clone();
+
+            async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                let Some(request) = testing::read_request(&mut stream).await else {
+                    return;
+                };
+
+                let first = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(request);
+                    seen.len() == 1
+                };
+                if !first {
+                    let answer = testing::response("200 OK", &[], "from the backend");
+                    let _ = stream.get_mut().write_all(&answer).await;
+                }
+            }
+        })
+        .await;
+
+        (addr, requests)
+    }
+
+    #[actix_web::test]
+    async fn head_lengths_are_passed_on_without_waiting() {
+        let backend = testing::listen(|stream| async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            testing::read_request(&mut stream).await;
+
+            let head = "HTTP/1.1 200 OK\r\nContent-Length: 10485760\r\n\r\n";
+            let _ = stream.get_mut().write_all(head.as_bytes()).await;
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        })
+        .await;
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-big",
+            backend.port(),
+            json!({})
+        )]))
+        .await;
+        let addr = serve(app_data(testing::config(&url)).await);
+
+        let started = Instant::now();
+        let response = raw(
+            addr,
+            "HEAD / HTTP/1.1\r\nHost: big.example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert!(head.contains("\r\ncontent-length: 10485760"), "{head}");
+        assert!(!head.contains("transfer-encoding"), "{head}");
+        assert_eq!(body, "");
+    }
+
+    #[actix_web::test]
+    async fn bodiless_statuses_are_not_waited_on() {
+        for status in ["304 Not Modified", "204 No Content"] {
+            let backend = testing::listen(move |stream| async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                testing::read_request(&mut stream).await;
+
+                // A length it has no business sending, and then nothing
+                let head =
+                    format!("HTTP/1.1 {status}\r\nContent-Length: 1000\r\nETag: \"v1\"\r\n\r\n");
+                let _ = stream.get_mut().write_all(head.as_bytes()).await;
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            })
+            .await;
+            let (url, _) = testing::docker(json!([testing::container(
+                "http-cached",
+                backend.port(),
+                json!({})
+            )]))
+            .await;
+            let data = app_data(testing::config(&url)).await;
+
+            let started = Instant::now();
+            let req = get("cached.example.com", "/").insert_header(("If-None-Match", "\"v1\""));
+            let (got, headers, body) = call(&data, req).await;
+            assert!(started.elapsed() < Duration::from_secs(1), "{status}");
+            assert_eq!(got.as_u16().to_string(), &status[..3]);
+            assert_eq!(headers.get(ETAG).unwrap(), "\"v1\"");
+            assert!(body.is_empty());
+        }
+    }
+
+    #[actix_web::test]
+    async fn small_bodies_are_buffered_and_others_streamed() {
+        for (body, chunked, buffered) in [
+            ("small", false, true),
+            ("much too big to be buffered", false, false),
+            ("small", true, false),
+        ] {
+            let (backend, requests) = hangs_up_once().await;
+            let (url, _) = testing::docker(json!([testing::container(
+                "http-foo",
+                backend.port(),
+                json!({})
+            )]))
+            .await;
+            let mut config = testing::config(&url);
+            config.stream_threshold = 16;
+            config.retries = 1;
+            let data = app_data(config).await;
+
+            let mut req = TestRequest::put()
+                .uri("/")
+                .insert_header((HOST, "foo.example.com"))
+                .set_payload(body);
+            req = match chunked {
+                true => req.insert_header((TRANSFER_ENCODING, "chunked")),
+                false => req.insert_header((CONTENT_LENGTH, body.len())),
+            };
+            let (status, _, _) = call(&data, req).await;
+
+            // Only a buffered body is still there to be sent again
+            let requests = requests.lock().unwrap();
+            let expected = if buffered {
+                StatusCode::OK
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            assert_eq!(status, expected, "{body:?}, chunked: {chunked}");
+            assert_eq!(requests.len(), if buffered { 2 } else { 1 });
+
+            let request = &requests[0];
+            assert_eq!(request.body, body.as_bytes());
+            if chunked {
+                assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+            } else {
+                assert_eq!(
+                    request.header("content-length"),
+                    Some(&*body.len().to_string())
+                );
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn statuses_are_remapped() {
+        let (backend, _) = testing::backend(|req| match req.target.as_str() {
+            "/teapot" => testing::response("418 I'm a teapot", &[], "short and stout"),
+            "/busy" => testing::response("503 Service Unavailable", &[], "busy"),
+            _ => testing::response("404 Not Found", &[], "nope"),
+        })
+        .await;
+        let labels = json!({ "dumbrouter.remap_status": "418:200, 503:500" });
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-foo",
+            backend.port(),
+            labels
+        )]))
+        .await;
+        let data = app_data(testing::config(&url)).await;
+
+        for (path, status, body) in [
+            ("/teapot", StatusCode::OK, "short and stout"),
+            ("/busy", StatusCode::INTERNAL_SERVER_ERROR, "busy"),
+            ("/other", StatusCode::NOT_FOUND, "nope"),
+        ] {
+            let (got, _, got_body) = call(&data, get("foo.example.com", path)).await;
+            assert_eq!((got, &got_body[..]), (status, body.as_bytes()), "{path}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn spoofed_forwarding_headers_never_reach_the_backend() {
+        let (config, requests) = one_backend("http-foo", json!({})).await;
+        let data = app_data(config).await;
+
+        let req = get("foo.example.com", "/")
+            .peer_addr("203.0.113.5:40000".parse().unwrap())
+            .insert_header(("X-Forwarded-For", "127.0.0.1"))
+            .insert_header(("X-Real-IP", "127.0.0.1"))
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("Forwarded", "for=127.0.0.1"));
+        call(&data, req).await;
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.header("x-forwarded-for"), Some("203.0.113.5"));
+        assert_eq!(request.header("x-real-ip"), Some("203.0.113.5"));
+        assert_eq!(request.header("x-forwarded-proto"), Some("http"));
+        assert_eq!(request.header("forwarded"), None);
+    }
+
+    #[actix_web::test]
+    async fn labeled_headers_are_added() {
+        let (config, requests) = one_backend(
+            "http-foo",
+            json!({
+                "dumbrouter.req_header.X-Api-Key": "secret",
+                "dumbrouter.req_header.X-Team": "web",
+                "dumbrouter.resp_header.X-Frame-Options": "DENY",
+                "dumbrouter.resp_header.X-Served-By": "foo",
+                "dumbrouter.resp_header.Bad Name": "skipped",
+            }),
+        )
+        .await;
+        let data = app_data(config).await;
+
+        let req = get("foo.example.com", "/").insert_header(("X-Api-Key", "from-client"));
+        let (status, headers, _) = call(&data, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("x-frame-options").unwrap(), "DENY");
+        assert_eq!(headers.get("x-served-by").unwrap(), "foo");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.header("x-api-key"), Some("secret"));
+        assert_eq!(request.header("x-team"), Some("web"));
+        assert_eq!(
+            request
+                .headers
+                .iter()
+                .filter(|(n, _)| n == "x-api-key")
+                .count(),
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn strict_mode_refuses_ambiguous_hosts() {
+        let (backend, requests) = testing::backend(ok).await;
+        let (url, _) = testing::docker(json!([
+            testing::container("http-foo", backend.port(), json!({})),
+            testing::container("http-foobar", backend.port(), json!({})),
+        ]))
+        .await;
+
+        for strict in [false, true] {
+            let mut config = testing::config(&url);
+            config.strict_match = strict;
+            let data = app_data(config).await;
+
+            let (status, _, body) = call(&data, get("foo.example.com", "/")).await;
+            if strict {
+                assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+                let body = String::from_utf8_lossy(&body);
+                assert!(
+                    body.starts_with("Ambiguous routing: foo matches containers of foo, foobar")
+                );
+            } else {
+                assert_eq!(status, StatusCode::OK);
+            }
+        }
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn ports_on_a_specific_ip_are_reached_there() {
+        let (backend, requests) = testing::backend(ok).await;
+        let mut pinned = testing::container("http-pinned", backend.port(), json!({}));
+        pinned["Ports"][0]["IP"] = json!("127.0.0.1");
+        let anywhere = testing::container("http-anywhere", backend.port(), json!({}));
+        let (url, _) = testing::docker(json!([pinned, anywhere])).await;
+
+        // Where every-interface ports are, and nothing listens
+        let mut config = testing::config(&url);
+        config.localhost_ip = "127.0.0.2".to_string();
+        let data = app_data(config).await;
+
+        let (status, _, _) = call(&data, get("pinned.example.com", "/")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let (status, _, _) = call(&data, get("anywhere.example.com", "/")).await;
+        assert_ne!(status, StatusCode::OK);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn paths_reach_the_backend_still_encoded() {
+        let (config, requests) = one_backend("http-files", json!({})).await;
+        let data = app_data(config).await;
+
+        let targets = [
+            "/files/a%2Fb",
+            "/files/a%20b+c",
+            "/files/caf%C3%A9",
+            "/files/what%3Fnot?really=%3F",
+            "/files/%25%23",
+        ];
+        for target in targets {
+            let (status, _, _) = call(&data, get("files.example.com", target)).await;
+            assert_eq!(status, StatusCode::OK, "{target}");
+        }
+
+        let sent: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.target.clone())
+            .collect();
+        assert_eq!(sent, targets);
+    }
+
+    #[actix_web::test]
+    async fn slashes_are_left_as_they_were() {
+        let (config, requests) = one_backend("http-api", json!({})).await;
+        let data = app_data(config).await;
+
+        for target in [
+            "/api",
+            "/api/",
+            "/",
+            "/?q=1",
+            "//double//",
+            "http://api.example.com",
+        ] {
+            let (status, _, _) = call(&data, get("api.example.com", target)).await;
+            assert_eq!(status, StatusCode::OK, "{target}");
+        }
+
+        let sent: Vec<_> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.target.clone())
+            .collect();
+        // An absolute URI with no path is a request for the root
+        assert_eq!(sent, ["/api", "/api/", "/", "/?q=1", "//double//", "/"]);
+    }
+
+    #[test]
+    fn degenerate_hosts_dont_panic() {
+        let parts = |host: &[&str]| host.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        for (host, service) in [
+            (&[][..], ""),
+            (&[""], ""),
+            (&["", ""], "_root"),
+            (&["", "", ""], ""),
+            (&[".", ".", "."], "."),
+            (&["www", "", ""], "_root"),
+            (&["", "example", "com"], ""),
+            (&["a", "", "example", "com"], "a."),
+            (&["localhost"], "localhost"),
+        ] {
+            assert_eq!(service_from_host_parts(parts(host)), service, "{host:?}");
+        }
+    }
+
+    #[test]
+    fn junk_hosts_are_invalid() {
+        for host in [
+            "foo.example.com",
+            "foo.example.com.",
+            "foo.example.com:8080",
+            "localhost",
+            "my_app.local",
+            "[::1]",
+            "[::1]:8080",
+            "[2001:db8::1]:443",
+        ] {
+            assert!(is_valid_host(host), "{host}");
+        }
+
+        let long = format!("{}.com", "a".repeat(252));
+        for host in [
+            "",
+            " ",
+            ".",
+            ":8080",
+            "foo..example.com",
+            ".example.com",
+            "foo bar.com",
+            "café.example.com",
+            "foo/bar",
+            "foo.com:http",
+            "[garbage",
+            "[garbage]",
+            "[::1",
+            "[::1]junk",
+            "[::1]:port",
+            "[127.0.0.1]",
+            long.as_str(),
+        ] {
+            assert!(!is_valid_host(host), "{host:?}");
+        }
+    }
+
+    #[test]
+    fn ports_come_off_after_the_brackets() {
+        for (full_host, host) in [
+            ("foo.example.com:8080", "foo.example.com"),
+            ("foo.example.com", "foo.example.com"),
+            ("foo.example.com:", "foo.example.com"),
+            ("[::1]:8080", "[::1]"),
+            ("[::1]", "[::1]"),
+            ("[2001:db8::1]:443", "[2001:db8::1]"),
+            ("[garbage", "[garbage"),
+            ("[::1]junk", "[::1]junk"),
+            ("foo.com:http", "foo.com:http"),
+        ] {
+            assert_eq!(without_port(full_host), host, "{full_host}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn junk_hosts_are_bad_requests() {
+        let (config, requests) = one_backend("http-foo", json!({})).await;
+        let data = app_data(config).await;
+
+        let (status, _, body) = call(&data, TestRequest::get().uri("/")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8_lossy(&body).contains("dumbrouter/"));
+
+        let long = format!("{}.example.com", "a".repeat(250));
+        let unreadable = HeaderValue::from_bytes(b"\xfffoo.example.com").unwrap();
+        for host in [
+            HeaderValue::from_static(""),
+            HeaderValue::from_static("   "),
+            HeaderValue::from_static("foo\t.example.com"),
+            HeaderValue::from_str(&long).unwrap(),
+            unreadable,
+        ] {
+            let req = TestRequest::get()
+                .uri("/")
+                .insert_header((HOST, host.clone()));
+            let (status, _, body) = call(&data, req).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{host:?}");
+            assert!(String::from_utf8_lossy(&body).contains("dumbrouter/"));
+        }
+
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn slow_bodies_time_out() {
+        let (mut config, _) = one_backend("http-upload", json!({})).await;
+        config.client_body_timeout = Some(Duration::from_millis(200));
+
+        // Whether it's read in whole first or streamed on
+        for stream_threshold in [64 * 1024, 0] {
+            config.stream_threshold = stream_threshold;
+            let data = app_data(config.clone()).await;
+            let app =
+                actix_test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+
+            // One byte of the ten, and then nothing
+            let trickle: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(
+                futures_util::stream::once(async { Ok(Bytes::from_static(b"a")) })
+                    .chain(futures_util::stream::pending()),
+            );
+            let req = TestRequest::post()
+                .uri("/")
+                .insert_header((HOST, "upload.example.com"))
+                .insert_header((CONTENT_LENGTH, "10"))
+                .to_request();
+            let (req, _) =
+                req.replace_payload(actix_web::dev::Payload::Stream { payload: trickle });
+
+            let started = Instant::now();
+            let res = actix_test::call_service(&app, req).await;
+            assert_eq!(
+                res.status(),
+                StatusCode::REQUEST_TIMEOUT,
+                "{stream_threshold}"
+            );
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+    }
+
+    #[actix_web::test]
+    async fn short_bodies_are_bad_gateways() {
+        let (backend, _) = testing::backend(|_| {
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\nConnection: close\r\n\r\nnot quite"
+                .to_vec()
+        })
+        .await;
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-short",
+            backend.port(),
+            json!({})
+        )]))
+        .await;
+        let data = app_data(testing::config(&url)).await;
+
+        let (status, _, body) = call(&data, get("short.example.com", "/")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(!String::from_utf8_lossy(&body).contains("not quite"));
+    }
+
+    #[actix_web::test]
+    async fn rewrites_are_applied_on_the_way() {
+        let rules = "set-header X-Tenant {path.1}; remove-header X-Secret; set-path-prefix /v2";
+        let (config, requests) =
+            one_backend("http-tenants", json!({ rewrite::REWRITE_LABEL: rules })).await;
+        let data = app_data(config).await;
+
+        let req =
+            get("tenants.example.com", "/acme/users?page=2").insert_header(("X-Secret", "hunter2"));
+        let (status, _, _) = call(&data, req).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].target, "/v2/acme/users?page=2");
+        assert_eq!(requests[0].header("x-tenant"), Some("acme"));
+        assert_eq!(requests[0].header("x-secret"), None);
+    }
+
+    #[actix_web::test]
+    async fn responses_over_the_cap_are_refused_or_cut_off() {
+        let (small, _) = testing::backend(|_| testing::response("200 OK", &[], "just right")).await;
+        let (big, _) =
+            testing::backend(|_| testing::response("200 OK", &[], &"x".repeat(100))).await;
+        let chunked = testing::listen(|stream| async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            testing::read_request(&mut stream).await;
+
+            let stream = stream.get_mut();
+            let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+            let _ = stream.write_all(head.as_bytes()).await;
+            for _ in 0..10 {
+                let _ = stream.write_all(b"a\r\nxxxxxxxxxx\r\n").await;
+            }
+            let _ = stream.write_all(b"0\r\n\r\n").await;
+        })
+        .await;
+        let (url, _) = testing::docker(json!([
+            testing::container("http-small", small.port(), json!({})),
+            testing::container("http-big", big.port(), json!({})),
+            testing::container("http-chunked", chunked.port(), json!({})),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        config.max_response = Some(16);
+        let data = app_data(config).await;
+
+        let (status, _, body) = call(&data, get("small.example.com", "/")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[..], b"just right");
+
+        // Its Content-Length gives it away before anything is sent
+        let (status, _, _) = call(&data, get("big.example.com", "/")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        // Its headers are already on their way by the time it's too big
+        let app =
+            actix_test::init_service(App::new().app_data(data.clone()).configure(routes)).await;
+        let res =
+            actix_test::call_service(&app, get("chunked.example.com", "/").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(actix_web::body::to_bytes(res.into_body()).await.is_err());
+    }
+
+    #[test]
+    fn the_longest_base_domain_wins() {
+        let mut config = Config::load(&Args::default()).unwrap();
+        // Longest first, as they're loaded
+        config.base_domains = ["apps.example.co.uk", "dev.example.com", "example.com"]
+            .map(String::from)
+            .to_vec();
+
+        for (host, service) in [
+            ("foo.example.com", "foo"),
+            ("a.b.example.com", "a.b"),
+            ("FOO.Example.COM", "foo"),
+            ("example.com", "_root"),
+            ("www.example.com", "_root"),
+            ("foo.dev.example.com", "foo"),
+            ("dev.example.com", "_root"),
+            ("foo.apps.example.co.uk", "foo"),
+            ("apps.example.co.uk", "_root"),
+            // Under none of them, so the last two labels come off
+            ("foo.example.co.uk", "foo.example"),
+            ("notexample.com", "_root"),
+            ("foo.notexample.com", "foo"),
+        ] {
+            assert_eq!(route(&config, host, host, 80).service, service, "{host}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejected_methods_are_told_what_would_work() {
+        let (config, _) = one_backend("http-foo", json!({})).await;
+        let data = app_data(config).await;
+
+        let made_up = Method::from_bytes(b"FROBNICATE").unwrap();
+        for method in [Method::TRACE, Method::CONNECT, Method::PATCH, made_up] {
+            let req = get("foo.example.com", "/").method(method.clone());
+            let (status, headers, _) = call(&data, req).await;
+            assert_eq!(status, StatusCode::NOT_IMPLEMENTED, "{method}");
+            assert_eq!(
+                headers.get("allow").unwrap(),
+                "GET, POST, PUT, DELETE, HEAD, OPTIONS",
+                "{method}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn forwarded_hosts_are_as_configured() {
+        let (backend, requests) = testing::backend(ok).await;
+        let port = backend.port();
+        let (url, _) = testing::docker(json!([
+            testing::container("http-plain", port, json!({})),
+            testing::container(
+                "http-strip",
+                port,
+                json!({ FORWARD_HOST_LABEL: "stripped" })
+            ),
+            testing::container(
+                "http-named",
+                port,
+                json!({ FORWARD_HOST_LABEL: "internal.svc" })
+            ),
+            testing::container("http-kept", port, json!({ FORWARD_HOST_LABEL: "original" })),
+        ]))
+        .await;
+
+        for (forward_host, host, sent) in [
+            (ForwardHost::Original, "plain", "plain.example.com:8443"),
+            (ForwardHost::Stripped, "plain", "plain.example.com"),
+            (
+                ForwardHost::Set("backend.local".to_string()),
+                "plain",
+                "backend.local",
+            ),
+            // Labels win over the setting
+            (ForwardHost::Original, "strip", "strip.example.com"),
+            (ForwardHost::Original, "named", "internal.svc"),
+            (ForwardHost::Stripped, "kept", "kept.example.com:8443"),
+        ] {
+            let mut config = testing::config(&url);
+            config.forward_host = forward_host.clone();
+            let data = app_data(config).await;
+
+            let host = format!("{host}.example.com:8443");
+            let (status, _, _) = call(&data, get(&host, "/")).await;
+            assert_eq!(status, StatusCode::OK);
+
+            let got = requests.lock().unwrap().pop().unwrap();
+            assert_eq!(
+                got.header("host"),
+                Some(sent),
+                "{forward_host:?} for {host}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn backends_are_hung_up_on_when_clients_leave() {
+        let hung_up = Arc::new(Mutex::new(None));
+        let noticed = hung_up.clone();
+
+        // Takes the request, never answers, and notes when it's hung up on
+        let backend = testing::listen(move |stream| {
+            let noticed = noticed.clone();
+            async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                testing::read_request(&mut stream).await;
+                let _ = stream.read(&mut [0; 1]).await;
+                *noticed.lock().unwrap() = Some(Instant::now());
+            }
+        })
+        .await;
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-slow",
+            backend.port(),
+            json!({})
+        )]))
+        .await;
+        let addr = serve(app_data(testing::config(&url)).await);
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: slow.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(client);
+        let left = Instant::now();
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let hung_up = hung_up.lock().unwrap().expect("backend wasn't hung up on");
+        assert!(hung_up.duration_since(left) < Duration::from_millis(300));
+    }
+
+    /// A backend answering with `chunks` of a chunked body, then hanging up.
+    async fn chunked_backend(chunks: &'static [&'static str]) -> SocketAddr {
+        testing::listen(move |stream| async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            testing::read_request(&mut stream).await;
+
+            let stream = stream.get_mut();
+            let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+            let _ = stream.write_all(head.as_bytes()).await;
+            for chunk in chunks {
+                let chunk = format!("{:x}\r\n{chunk}\r\n", chunk.len());
+                let _ = stream.write_all(chunk.as_bytes()).await;
+            }
+            let _ = stream.write_all(b"0\r\n\r\n").await;
+        })
+        .await
+    }
+
+    #[actix_web::test]
+    async fn http_1_0_clients_need_a_host_and_get_no_chunks() {
+        let backend = chunked_backend(&["hello ", "world"]).await;
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-old",
+            backend.port(),
+            json!({})
+        )]))
+        .await;
+        let addr = serve(app_data(testing::config(&url)).await);
+
+        let response = raw(addr, "GET / HTTP/1.0\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(" 400 Bad Request"), "{head}");
+        assert!(body.starts_with("A Host header is needed"), "{body}");
+
+        // HTTP/1.0 closes by default, so hanging up is all that says so;
+        // `raw` reads until it does
+        let response = raw(addr, "GET / HTTP/1.0\r\nHost: old.example.com\r\n\r\n").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(" 200 OK"), "{head}");
+        assert!(
+            !head.to_ascii_lowercase().contains("transfer-encoding"),
+            "{head}"
+        );
+        assert_eq!(body, "hello world");
+    }
+
+    #[actix_web::test]
+    async fn each_hop_frames_its_own_bodies() {
+        let chunks = chunked_backend(&["hello ", "world"]).await;
+        let (fixed, requests) = testing::backend(|request| {
+            testing::response("200 OK", &[], std::str::from_utf8(&request.body).unwrap())
+        })
+        .await;
+        let (url, _) = testing::docker(json!([
+            testing::container("http-chunks", chunks.port(), json!({})),
+            testing::container("http-fixed", fixed.port(), json!({})),
+        ]))
+        .await;
+        let addr = serve(app_data(testing::config(&url)).await);
+
+        // A chunked upload goes on chunked, with no length made up for it
+        let response = raw(
+            addr,
+            "POST / HTTP/1.1\r\nHost: fixed.example.com\r\nConnection: close\r\n\
+             Transfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n",
+        )
+        .await;
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(request.header("content-length"), None);
+        assert_eq!(request.body, b"hello world");
+
+        // and a response with a length comes back with exactly that length
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let head = head.to_ascii_lowercase();
+        assert!(head.contains("\r\ncontent-length: 11\r\n"), "{head}");
+        assert!(!head.contains("transfer-encoding"), "{head}");
+        assert_eq!(body, "hello world");
+
+        // A length-framed upload keeps its length
+        raw(
+            addr,
+            "POST / HTTP/1.1\r\nHost: fixed.example.com\r\nConnection: close\r\n\
+             Content-Length: 5\r\n\r\nhello",
+        )
+        .await;
+        let request = requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request.header("content-length"), Some("5"));
+        assert_eq!(request.header("transfer-encoding"), None);
+
+        // A chunked response is chunked again for a client that speaks it
+        let response = raw(
+            addr,
+            "GET / HTTP/1.1\r\nHost: chunks.example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let head = head.to_ascii_lowercase();
+        assert!(head.contains("\r\ntransfer-encoding: chunked"), "{head}");
+        assert!(!head.contains("content-length"), "{head}");
+        assert!(body.ends_with("0\r\n\r\n"), "{body:?}");
+        assert!(
+            body.contains("hello ") && body.contains("world"),
+            "{body:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn traces_are_passed_on_to_backends() {
+        let (mut config, requests) = one_backend("http-foo", json!({})).await;
+        config.propagate_traces = true;
+        let data = app_data(config).await;
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let header = format!("00-{trace_id}-00f067aa0ba902b7-01");
+        call(
+            &data,
+            get("foo.example.com", "/").insert_header(("traceparent", header)),
+        )
+        .await;
+        call(&data, get("foo.example.com", "/")).await;
+
+        let requests = requests.lock().unwrap();
+        let traceparent = |i: usize| -> Vec<String> {
+            let header = requests[i].header("traceparent").unwrap();
+            header.split('-').map(str::to_string).collect()
+        };
+
+        let continued = traceparent(0);
+        assert_eq!(continued[1], trace_id);
+        assert_ne!(continued[2], "00f067aa0ba902b7");
+        assert_eq!(continued[3], "01");
+
+        let started = traceparent(1);
+        assert_eq!(started.len(), 4);
+        assert_ne!(started[1], trace_id);
+        assert_eq!(started[1].len(), 32);
+    }
+
+    #[actix_web::test]
+    async fn connections_past_the_limit_wait() {
+        let (mut config, _) = one_backend("http-foo", json!({})).await;
+        config.max_connections = Some(2);
+        let addr = serve(app_data(config).await);
+
+        let request = b"GET / HTTP/1.1\r\nHost: foo.example.com\r\n\r\n";
+        let mut answer = [0; 64];
+
+        // Kept alive, so they hold both slots
+        let mut held = vec![];
+        for _ in 0..2 {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            assert!(stream.read(&mut answer).await.unwrap() > 0);
+            held.push(stream);
+        }
+
+        let mut waiting = tokio::net::TcpStream::connect(addr).await.unwrap();
+        waiting.write_all(request).await.unwrap();
+        let answered = tokio::time::timeout(Duration::from_millis(300), waiting.read(&mut answer));
+        assert!(answered.await.is_err(), "answered past the limit");
+
+        // actix only goes back to accepting once it's two under the limit
+        drop(held);
+        let answered = tokio::time::timeout(Duration::from_secs(5), waiting.read(&mut answer));
+        assert!(answered.await.unwrap().unwrap() > 0);
+        assert!(answer.starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    #[actix_web::test]
+    async fn slow_requests_are_warned_about() {
+        let (fast, _) = testing::backend(ok).await;
+        let (slow, _) = testing::backend_with(ok, Duration::from_millis(300)).await;
+        let (url, _) = testing::docker(json!([
+            testing::container("http-fast", fast.port(), json!({})),
+            testing::container("http-slow", slow.port(), json!({})),
+        ]))
+        .await;
+        let mut config = testing::config(&url);
+        config.slow_threshold = Some(Duration::from_millis(150));
+        let data = app_data(config).await;
+
+        log::capture();
+        let (status, _, _) = call(&data, get("fast.example.com", "/")).await;
+        assert_eq!(status, StatusCode::OK);
+        let lines = log::captured();
+        assert!(
+            !lines.iter().any(|line| line.contains("Slow request")),
+            "{lines:?}"
+        );
+
+        log::capture();
+        let (status, _, _) = call(&data, get("slow.example.com", "/")).await;
+        assert_eq!(status, StatusCode::OK);
+        let lines = log::captured();
+        let warning = lines.iter().find(|line| line.contains("Slow request"));
+        let warning = warning.unwrap_or_else(|| panic!("not warned about: {lines:?}"));
+        assert!(warning.starts_with("WARN: "), "{warning}");
+        assert!(warning.contains("service=slow"), "{warning}");
+    }
+
+    #[actix_web::test]
+    async fn head_can_be_sent_on_as_get() {
+        // Only knows GET
+        let (backend, requests) = testing::backend(|request| match &request.method[..] {
+            "GET" => testing::response("200 OK", &[("X-Kind", "page")], "the whole page"),
+            _ => testing::response("405 Method Not Allowed", &[], ""),
+        })
+        .await;
+        let (url, _) = testing::docker(json!([
+            testing::container("http-plain", backend.port(), json!({})),
+            testing::container(
+                "http-getter",
+                backend.port(),
+                json!({ HEAD_AS_GET_LABEL: "true" })
+            ),
+        ]))
+        .await;
+        let addr = serve(app_data(testing::config(&url)).await);
+
+        let response = raw(
+            addr,
+            "HEAD / HTTP/1.1\r\nHost: getter.example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let head = head.to_ascii_lowercase();
+        assert!(head.starts_with("http/1.1 200 ok"), "{head}");
+        assert!(head.contains("\r\nx-kind: page"), "{head}");
+        assert!(head.contains("\r\ncontent-length: 14"), "{head}");
+        assert_eq!(body, "");
+        assert_eq!(requests.lock().unwrap().pop().unwrap().method, "GET");
+
+        let response = raw(
+            addr,
+            "HEAD / HTTP/1.1\r\nHost: plain.example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+        assert_eq!(requests.lock().unwrap().pop().unwrap().method, "HEAD");
+    }
+
+    #[actix_web::test]
+    async fn backends_that_stall_mid_body_are_given_up_on() {
+        // Starts on the body, with a Content-Length for `/fixed` and chunked
+        // otherwise, and then goes quiet
+        let backend = testing::listen(|stream| async move {
+            let mut stream = tokio::io::BufReader::new(stream);
+            let Some(request) = testing::read_request(&mut stream).await else {
+                return;
+            };
+
+            let start: &[u8] = match &request.target[..] {
+                "/fixed" => b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello ",
+                _ => b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n",
+            };
+            let _ = stream.get_mut().write_all(start).await;
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        })
+        .await;
+        let (url, _) = testing::docker(json!([testing::container(
+            "http-stall",
+            backend.port(),
+            json!({})
+        )]))
+        .await;
+        let mut config = testing::config(&url);
+        config.read_timeout = Some(Duration::from_millis(200));
+        let data = app_data(config).await;
+
+        let (status, _, _) = call(&data, get("stall.example.com", "/fixed")).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+
+        // Already on its way, so all that can be done is to stop short
+        let addr = serve(data);
+        let response = raw(
+            addr,
+            "GET /chunked HTTP/1.1\r\nHost: stall.example.com\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+        assert!(body.contains("hello "), "{body:?}");
+        assert!(!body.ends_with("0\r\n\r\n"), "{body:?}");
+    }
+
+    /// dumbrouter with `config` on two ports of its own, and a container for
+    /// `app` on each of them.
+    async fn two_listeners(mut config: impl FnMut(&mut Config)) -> [SocketAddr; 2] {
+        let (first, _) = testing::backend(|_| testing::response("200 OK", &[], "first")).await;
+        let (second, _) = testing::backend(|_| testing::response("200 OK", &[], "second")).await;
+
+        let listeners = [(); 2].map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap());
+        let addrs = listeners.each_ref().map(|l| l.local_addr().unwrap());
+        let (url, _) = testing::docker(json!([
+            testing::container(
+                "http-app",
+                first.port(),
+                json!({ "dumbrouter.listen_port": addrs[0].port().to_string() })
+            ),
+            testing::container(
+                "http-app-1",
+                second.port(),
+                json!({ "dumbrouter.listen_port": addrs[1].port().to_string() })
+            ),
+        ]))
+        .await;
+        let mut routing = testing::config(&url);
+        config(&mut routing);
+        let data = app_data(routing).await;
+
+        let mut server =
+            HttpServer::new(move || App::new().app_data(data.clone()).configure(routes)).workers(1);
+        for listener in listeners {
+            server = server.listen(listener).unwrap();
+        }
+        actix_web::rt::spawn(server.run());
+
+        addrs
+    }
+
+    async fn body_from(addr: SocketAddr, host: &str) -> String {
+        let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+        let response = raw(addr, &request).await;
+        response.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[actix_web::test]
+    async fn ports_pick_the_backend_in_port_aware_mode() {
+        let addrs = two_listeners(|config| config.port_aware = true).await;
+
+        assert_eq!(body_from(addrs[0], "app.example.com").await, "first");
+        assert_eq!(body_from(addrs[1], "app.example.com").await, "second");
+
+        // The port in the Host header wins over the one it came in on
+        let host = format!("app.example.com:{}", addrs[1].port());
+        assert_eq!(body_from(addrs[0], &host).await, "second");
+    }
+
+    #[actix_web::test]
+    async fn ports_dont_matter_by_default() {
+        let addrs = two_listeners(|_| {}).await;
+
+        // Either container, whichever port it comes in on
+        let mut seen = HashSet::new();
+        for _ in 0..20 {
+            for addr in addrs {
+                seen.insert(body_from(addr, "app.example.com").await);
+            }
+        }
+        assert_eq!(
+            seen,
+            HashSet::from(["first".to_string(), "second".to_string()])
+        );
+    }
+
+    #[actix_web::test]
+    async fn unanswered_pings_give_up() {
+        // Takes the connection, never says anything
+        let silent = testing::listen(|mut stream| async move {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            let _ = stream.shutdown().await;
+        })
+        .await;
+        let config = testing::config(&format!("http://{silent}"));
+        let host = DockerHost::connect(&config.docker_hosts[0], &config).unwrap();
+
+        let started = Instant::now();
+        let reachable = ping_docker_hosts(&[host], Duration::from_millis(200)).await;
+
+        assert_eq!(reachable, 0);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 }
@@ -0,0 +1,210 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::context::RequestContext;
+use actix_web::http::StatusCode;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What requests for hosts that don't belong to any service are counted as,
+/// so that random hostnames can't create new series.
+const UNKNOWN_SERVICE: &str = "_unknown";
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Per bucket, not cumulative
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+
+        if let Some(i) = BUCKETS.iter().position(|&le| secs <= le) {
+            self.counts[i] += 1;
+        }
+
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, service: &str) {
+        let mut cumulative = 0;
+
+        for (le, count) in BUCKETS.iter().zip(self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{service=\"{service}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{service=\"{service}\",le=\"+Inf\"}} {}",
+            self.count
+        );
+        let _ = writeln!(out, "{name}_sum{{service=\"{service}\"}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{service=\"{service}\"}} {}", self.count);
+    }
+}
+
+#[derive(Default)]
+struct ServiceMetrics {
+    requests: BTreeMap<u16, u64>,
+    duration: Histogram,
+    discovery_duration: Histogram,
+    upstream_duration: Histogram,
+    in_flight: i64,
+    dropped_headers: u64,
+}
+
+/// Per-service request metrics, in Prometheus' text format.
+#[derive(Default)]
+pub struct Metrics {
+    services: Mutex<HashMap<String, ServiceMetrics>>,
+}
+
+/// Counts a request as in flight for as long as it's alive.
+pub struct InFlight<'a> {
+    metrics: &'a Metrics,
+    service: String,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut services = self.metrics.services.lock().unwrap();
+        services.entry(self.service.clone()).or_default().in_flight -= 1;
+    }
+}
+
+impl Metrics {
+    /// Records a finished request.
+    pub fn record(&self, ctx: &RequestContext, status: StatusCode) {
+        let service = match &ctx.service {
+            Some(service) if ctx.service_known => service.as_str(),
+            _ => UNKNOWN_SERVICE,
+        };
+
+        let mut services = self.services.lock().unwrap();
+        let metrics = services.entry(service.to_string()).or_default();
+
+        *metrics.requests.entry(status.as_u16()).or_default() += 1;
+        metrics.duration.observe(ctx.started.elapsed());
+        metrics.dropped_headers += ctx.dropped_headers;
+
+        if let Some(discovery_time) = ctx.discovery_time {
+            metrics.discovery_duration.observe(discovery_time);
+        }
+
+        if let Some(upstream_time) = ctx.upstream_time {
+            metrics.upstream_duration.observe(upstream_time);
+        }
+    }
+
+    /// Marks a request to `service` as in flight until the guard is dropped.
+    pub fn in_flight(&self, service: &str) -> InFlight<'_> {
+        let mut services = self.services.lock().unwrap();
+        services.entry(service.to_string()).or_default().in_flight += 1;
+
+        InFlight {
+            metrics: self,
+            service: service.to_string(),
+        }
+    }
+
+    /// `snapshot_age` is how old the container listing is, if there is one.
+    pub fn render(&self, snapshot_age: Option<Duration>) -> String {
+        let services = self.services.lock().unwrap();
+        let mut names = services.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut out = String::new();
+
+        out.push_str("# TYPE dumbrouter_requests_total counter\n");
+        for name in &names {
+            for (status, count) in &services[*name].requests {
+                let _ = writeln!(
+                    out,
+                    "dumbrouter_requests_total{{service=\"{name}\",status=\"{status}\"}} {count}"
+                );
+            }
+        }
+
+        out.push_str("# TYPE dumbrouter_request_duration_seconds histogram\n");
+        for name in &names {
+            services[*name]
+                .duration
+                .render(&mut out, "dumbrouter_request_duration_seconds", name);
+        }
+
+        out.push_str("# TYPE dumbrouter_discovery_duration_seconds histogram\n");
+        for name in &names {
+            services[*name].discovery_duration.render(
+                &mut out,
+                "dumbrouter_discovery_duration_seconds",
+                name,
+            );
+        }
+
+        out.push_str("# TYPE dumbrouter_upstream_duration_seconds histogram\n");
+        for name in &names {
+            services[*name].upstream_duration.render(
+                &mut out,
+                "dumbrouter_upstream_duration_seconds",
+                name,
+            );
+        }
+
+        out.push_str("# TYPE dumbrouter_in_flight_requests gauge\n");
+        for name in &names {
+            let _ = writeln!(
+                out,
+                "dumbrouter_in_flight_requests{{service=\"{name}\"}} {}",
+                services[*name].in_flight
+            );
+        }
+
+        out.push_str("# TYPE dumbrouter_dropped_headers_total counter\n");
+        for name in &names {
+            let _ = writeln!(
+                out,
+                "dumbrouter_dropped_headers_total{{service=\"{name}\"}} {}",
+                services[*name].dropped_headers
+            );
+        }
+
+        if let Some(age) = snapshot_age {
+            out.push_str("# TYPE dumbrouter_discovery_snapshot_age_seconds gauge\n");
+            let _ = writeln!(
+                out,
+                "dumbrouter_discovery_snapshot_age_seconds {}",
+                age.as_secs_f64()
+            );
+        }
+
+        out
+    }
+}
@@ -0,0 +1,139 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::discovery::Backend;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Label sending a copy of every request for a container to a backend of the
+/// service at its value, whose answers are thrown away.
+const MIRROR_LABEL: &str = "dumbrouter.mirror";
+
+/// Label with the percentage of requests `dumbrouter.mirror` copies (100 if
+/// it's not there).
+const MIRROR_PERCENT_LABEL: &str = "dumbrouter.mirror_percent";
+
+/// How long a mirrored request gets without `DUMBROUTER_READ_TIMEOUT_SECS`,
+/// so that a stuck mirror doesn't pile up requests forever.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The service a request to `backend` is to be copied to, if any.  Requests
+/// are picked at random, `dumbrouter.mirror_percent` of them.
+pub fn sampled(backend: &Backend) -> Option<&str> {
+    let service = backend.label(MIRROR_LABEL)?;
+
+    let percent = match backend.label(MIRROR_PERCENT_LABEL).map(str::parse::<f64>) {
+        None => 100.0,
+        Some(Ok(percent)) if (0.0..=100.0).contains(&percent) => percent,
+        Some(_) => {
+            log!(
+                "WARN: Container {} has a bad mirror percentage, not mirroring",
+                backend.name
+            );
+            return None;
+        }
+    };
+
+    (rand::random::<f64>() * 100.0 < percent).then_some(service)
+}
+
+/// How many mirrored requests can be waiting on answers at once.  Past that,
+/// the mirror is slower than the real thing, and copies are dropped rather
+/// than piled up.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Sends the copies `dumbrouter.mirror` asks for.
+pub struct Mirror {
+    in_flight: Arc<Semaphore>,
+}
+
+impl Mirror {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Sends `request` (request `id`'s copy for `service`) in the background,
+    /// reading and throwing away the answer.  Nothing about it can hold up or
+    /// fail the real request: the most it does is log a warning.
+    pub fn send(
+        &self,
+        client: reqwest::Client,
+        mut request: reqwest::Request,
+        timeout: Option<Duration>,
+        id: String,
+        service: String,
+    ) {
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            log!(
+                "WARN: [{id}] Too many mirrored requests to {service} in flight, dropping this one"
+            );
+            return;
+        };
+
+        *request.timeout_mut() = Some(timeout.unwrap_or(DEFAULT_TIMEOUT));
+
+        tokio::spawn(async move {
+            match client.execute(request).await {
+                Ok(mut res) => while let Ok(Some(_)) = res.chunk().await {},
+                Err(err) => log!("WARN: [{id}] Mirroring to {service} failed: {err}"),
+            }
+            drop(permit);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn copies_past_the_cap_are_dropped() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = connections.clone();
+
+        // Takes every connection, never answers
+        let silent = testing::listen(move |stream| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                drop(stream);
+            }
+        })
+        .await;
+
+        let mirror = Mirror::new();
+        let client = reqwest::Client::new();
+        for n in 0..MAX_IN_FLIGHT + 10 {
+            let request = client.get(format!("http://{silent}/")).build().unwrap();
+            mirror.send(
+                client.clone(),
+                request,
+                None,
+                n.to_string(),
+                "shadow".to_string(),
+            );
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(connections.load(Ordering::SeqCst), MAX_IN_FLIGHT);
+        assert_eq!(mirror.in_flight.available_permits(), 0);
+    }
+}
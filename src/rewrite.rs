@@ -0,0 +1,223 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::discovery::Backend;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// Label with `;`-separated rules changing requests before they're sent to a
+/// container, e.g. `set-header X-Tenant {path.1}; set-path-prefix /api`.
+pub const REWRITE_LABEL: &str = "dumbrouter.rewrite";
+
+/// One of the things `dumbrouter.rewrite` can do.  There's deliberately not
+/// much: anything cleverer belongs in the backend.
+enum Rule {
+    /// `set-header <name> <value>`, where `{path.N}` in the value is the Nth
+    /// segment of the request's path
+    SetHeader(HeaderName, String),
+    /// `remove-header <name>`
+    RemoveHeader(HeaderName),
+    /// `set-path-prefix <prefix>`, put in front of the request's path
+    SetPathPrefix(String),
+}
+
+/// Parses all of `rules`, so that none of them are applied if any are wrong.
+fn parse(rules: &str) -> Result<Vec<Rule>, String> {
+    let header =
+        |name: &str| HeaderName::try_from(name).map_err(|_| format!("Bad header name {name:?}"));
+
+    rules
+        .split(';')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let mut words = rule.splitn(3, char::is_whitespace);
+
+            match (words.next(), words.next(), words.next().map(str::trim)) {
+                (Some("set-header"), Some(name), Some(value)) => {
+                    Ok(Rule::SetHeader(header(name)?, value.to_string()))
+                }
+                (Some("remove-header"), Some(name), None) => Ok(Rule::RemoveHeader(header(name)?)),
+                (Some("set-path-prefix"), Some(prefix), None) if prefix.starts_with('/') => Ok(
+                    Rule::SetPathPrefix(prefix.trim_end_matches('/').to_string()),
+                ),
+                _ => Err(format!("Don't know what to do with {rule:?}")),
+            }
+        })
+        .collect()
+}
+
+/// Applies `backend`'s `dumbrouter.rewrite` rules to a request for `target`
+/// (its path and query) with `headers`, returning the target to send it to.
+/// Bad rules are complained about and nothing is changed.
+pub fn apply(backend: &Backend, headers: &mut HeaderMap, target: &str) -> String {
+    let Some(rules) = backend.label(REWRITE_LABEL) else {
+        return target.to_string();
+    };
+
+    let rules = match parse(rules) {
+        Ok(rules) => rules,
+        Err(err) => {
+            log!("WARN: Container {} has a bad rewrite: {err}", backend.name);
+            return target.to_string();
+        }
+    };
+
+    let path = target.split('?').next().unwrap_or_default();
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+    let mut prefix = String::new();
+
+    for rule in rules {
+        match rule {
+            Rule::SetHeader(name, template) => {
+                let value = fill_in(&template, &segments);
+
+                match HeaderValue::from_str(&value) {
+                    Ok(value) => {
+                        headers.insert(name, value);
+                    }
+                    Err(_) => log!(
+                        "WARN: Container {} rewrote {name} to a bad value {value:?}",
+                        backend.name
+                    ),
+                }
+            }
+            Rule::RemoveHeader(name) => {
+                headers.remove(name);
+            }
+            Rule::SetPathPrefix(new) => prefix = new,
+        }
+    }
+
+    format!("{prefix}{target}")
+}
+
+/// `template` with every `{path.N}` replaced by the Nth (from 1) of
+/// `segments`, or nothing if there aren't that many.
+fn fill_in(template: &str, segments: &[&str]) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{path.") {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + "{path.".len()..];
+
+        let placeholder = after.split_once('}').and_then(|(n, tail)| {
+            let n = n.parse::<usize>().ok().filter(|&n| n > 0)?;
+            Some((n, tail))
+        });
+
+        match placeholder {
+            Some((n, tail)) => {
+                filled.push_str(segments.get(n - 1).copied().unwrap_or_default());
+                rest = tail;
+            }
+            _ => {
+                filled.push_str("{path.");
+                rest = after;
+            }
+        }
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn backend(rules: &str) -> Backend {
+        Backend {
+            name: "/http-rewritten".to_string(),
+            addr: "127.0.0.1:80".to_string(),
+            docker_host: "local".to_string(),
+            labels: HashMap::from([(REWRITE_LABEL.to_string(), rules.to_string())]),
+        }
+    }
+
+    #[test]
+    fn rules_are_checked_all_at_once() {
+        let rules =
+            parse(" set-header X-Tenant {path.1} ; remove-header cookie;; set-path-prefix /api/ ;")
+                .unwrap();
+        assert!(matches!(
+            &rules[..],
+            [
+                Rule::SetHeader(name, value),
+                Rule::RemoveHeader(removed),
+                Rule::SetPathPrefix(prefix),
+            ] if name == "x-tenant" && value == "{path.1}" && removed == "cookie" && prefix == "/api"
+        ));
+
+        for bad in [
+            "set-header X-Tenant",
+            "set-header Bad:Name value",
+            "remove-header cookie please",
+            "set-path-prefix api",
+            "set-path-prefix /a /b",
+            "delete-everything",
+            "set-header X-Ok fine; frobnicate",
+        ] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn placeholders_are_path_segments() {
+        let segments = ["tenants", "acme", ""];
+
+        for (template, filled) in [
+            ("{path.2}", "acme"),
+            ("{path.1}/{path.2}!", "tenants/acme!"),
+            ("{path.3}{path.4}", ""),
+            ("{path.0}", "{path.0}"),
+            ("{path.x}", "{path.x}"),
+            ("{path.2", "{path.2"),
+            ("{path.{path.2}}", "{path.acme}"),
+            ("no placeholders", "no placeholders"),
+        ] {
+            assert_eq!(fill_in(template, &segments), filled, "{template}");
+        }
+    }
+
+    #[test]
+    fn each_rule_is_applied() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_static("a=b"));
+        headers.insert("x-kept", HeaderValue::from_static("yes"));
+
+        let rules = "set-header X-Tenant {path.2}; remove-header cookie; set-path-prefix /v1/";
+        let target = apply(&backend(rules), &mut headers, "/tenants/acme/users?page=2");
+
+        assert_eq!(target, "/v1/tenants/acme/users?page=2");
+        assert_eq!(headers["x-tenant"], "acme");
+        assert_eq!(headers["x-kept"], "yes");
+        assert!(!headers.contains_key("cookie"));
+    }
+
+    #[test]
+    fn bad_rules_change_nothing() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_static("a=b"));
+
+        let rules = "remove-header cookie; set-path-prefix nope";
+        let target = apply(&backend(rules), &mut headers, "/users");
+
+        assert_eq!(target, "/users");
+        assert_eq!(headers["cookie"], "a=b");
+    }
+}
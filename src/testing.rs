@@ -0,0 +1,276 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Stand-ins for Docker and for backends, so that tests can route real
+//! requests without either.
+
+use crate::cli::Args;
+use crate::config::{Config, DockerEndpoint};
+use crate::discovery::{Discovery, DockerHost};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Every request a stand-in has been sent, in order.
+pub type Requests = Arc<Mutex<Vec<Request>>>;
+
+/// A request as a stand-in got it.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path and query, as sent
+    pub target: String,
+    /// With lowercased names, in the order they came
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// When it started coming in
+    pub at: Instant,
+}
+
+impl Request {
+    /// The first value of header `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The default config, except that containers are listed from the Docker at
+/// `docker` and published ports are reached on loopback.
+pub fn config(docker: &str) -> Config {
+    let mut config = Config::load(&Args::default()).unwrap();
+    config.docker_hosts = vec![DockerEndpoint {
+        url: docker.to_string(),
+        backend_host: None,
+        tls_dir: None,
+    }];
+    config.localhost_ip = "127.0.0.1".to_string();
+    config
+}
+
+/// Connects to `config`'s Docker hosts and lists their containers.
+pub async fn discovery(config: &Config) -> Discovery {
+    let hosts = config
+        .docker_hosts
+        .iter()
+        .map(|endpoint| DockerHost::connect(endpoint, config).unwrap())
+        .collect();
+
+    let discovery = Discovery::new(hosts, config);
+    discovery.refresh().await.unwrap();
+    discovery
+}
+
+/// A running container called `name` with `labels`, publishing its port 80 as
+/// `port` on every interface.
+pub fn container(name: &str, port: u16, labels: Value) -> Value {
+    json!({
+        "Id": name,
+        "Names": [format!("/{name}")],
+        "State": "running",
+        "Status": "Up 5 minutes",
+        "Labels": labels,
+        "Ports": [{ "IP": "0.0.0.0", "PrivatePort": 80, "PublicPort": port, "Type": "tcp" }],
+    })
+}
+
+/// `container`, as Podman's Docker-compatible API lists it: no `/` in front
+/// of the name, and the port published for IPv4 without an IP and again for
+/// IPv6.
+pub fn podman_container(name: &str, port: u16, labels: Value) -> Value {
+    json!({
+        "Id": name,
+        "Names": [name],
+        "State": "running",
+        "Status": "Up 5 minutes",
+        "Labels": labels,
+        "Ports": [
+            { "PrivatePort": 80, "PublicPort": port, "Type": "tcp" },
+            { "IP": "::", "PrivatePort": 80, "PublicPort": port, "Type": "tcp" },
+        ],
+    })
+}
+
+/// Accepts connections on a port of its own, handing each one to `handle`.
+pub async fn listen<F, Fut>(handle: F) -> SocketAddr
+where
+    F: Fn(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle(stream));
+        }
+    });
+
+    addr
+}
+
+/// Reads a request's head, and its body if it has a Content-Length or is
+/// chunked.  `None` once the connection is closed (or the request is junk).
+pub async fn read_request<R: AsyncBufReadExt + Unpin>(stream: &mut R) -> Option<Request> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let at = Instant::now();
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        stream.read_line(&mut line).await.ok()?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        target,
+        headers,
+        body: vec![],
+        at,
+    };
+
+    if request.header("transfer-encoding") == Some("chunked") {
+        loop {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+            let size = usize::from_str_radix(line.trim(), 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            stream.read_exact(&mut chunk).await.ok()?;
+
+            if size == 0 {
+                break;
+            }
+            request.body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = request.header("content-length") {
+        let mut body = vec![0; length.parse().ok()?];
+        stream.read_exact(&mut body).await.ok()?;
+        request.body = body;
+    }
+
+    Some(request)
+}
+
+/// A whole response, with a Content-Length for `body` and the connection
+/// closed after it.
+pub fn response(status: &str, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {status}\r\n");
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    ));
+    response.into_bytes()
+}
+
+/// A backend that answers every request with whatever `respond` makes of it,
+/// one request per connection.
+pub async fn backend(respond: fn(&Request) -> Vec<u8>) -> (SocketAddr, Requests) {
+    backend_with(respond, Duration::ZERO).await
+}
+
+/// `backend`, taking `delay` to answer each request once it's in.
+pub async fn backend_with(
+    respond: fn(&Request) -> Vec<u8>,
+    delay: Duration,
+) -> (SocketAddr, Requests) {
+    let requests = Requests::default();
+    let seen = requests.clone();
+
+    let addr = listen(move |stream| {
+        let seen = seen.clone();
+
+        async move {
+            let mut stream = BufReader::new(stream);
+            let Some(request) = read_request(&mut stream).await else {
+                return;
+            };
+
+            let answer = respond(&request);
+            seen.lock().unwrap().push(request);
+            tokio::time::sleep(delay).await;
+            let _ = stream.get_mut().write_all(&answer).await;
+        }
+    })
+    .await;
+
+    (addr, requests)
+}
+
+/// What a stand-in Docker lists, which tests can change as they go.
+pub type Listing = Arc<Mutex<Value>>;
+
+/// A Docker daemon that answers `/_ping`, and every listing (of containers or
+/// of Swarm services) with `listing`.  Returns its URL.
+pub async fn docker(listing: Value) -> (String, Requests) {
+    docker_with(Arc::new(Mutex::new(listing)), Duration::ZERO).await
+}
+
+/// `docker`, listing whatever `listing` is at the time, and taking `delay`
+/// to answer anything.
+pub async fn docker_with(listing: Listing, delay: Duration) -> (String, Requests) {
+    let requests = Requests::default();
+    let seen = requests.clone();
+
+    let addr = listen(move |stream| {
+        let seen = seen.clone();
+        let listing = listing.clone();
+
+        async move {
+            let mut stream = BufReader::new(stream);
+            let Some(request) = read_request(&mut stream).await else {
+                return;
+            };
+
+            tokio::time::sleep(delay).await;
+
+            let listing = listing.lock().unwrap().to_string();
+            let json = [("Content-Type", "application/json")];
+            let path = request.target.split('?').next().unwrap_or_default();
+            let answer = match (request.method.as_str(), path) {
+                ("GET", path) if path.ends_with("/_ping") => response("200 OK", &[], "OK"),
+                ("GET", path)
+                    if path.ends_with("/containers/json") || path.ends_with("/services") =>
+                {
+                    response("200 OK", &json, &listing)
+                }
+                _ => response("404 Not Found", &json, r#"{"message":"page not found"}"#),
+            };
+
+            seen.lock().unwrap().push(request);
+            let _ = stream.get_mut().write_all(&answer).await;
+        }
+    })
+    .await;
+
+    (format!("http://{addr}"), requests)
+}
@@ -0,0 +1,326 @@
+// dumbrouter - Intentionally dumb Docker name-based HTTP router
+// Copyright (C) 2022 Violet McKinney <opensource@viomck.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::context::RequestContext;
+use crate::VERSION;
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use serde_json::{json, Value};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often finished spans are sent off.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the collector gets to take an export.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spans beyond this many are dropped until the next export.  Keeps an
+/// unreachable collector from eating all our memory.
+const MAX_QUEUED_SPANS: usize = 2048;
+
+/// Where a request's span sits in its trace.
+pub struct TraceContext {
+    trace_id: String,
+    span_id: String,
+    /// The client's span, when it sent a `traceparent`
+    parent_span_id: Option<String>,
+    flags: String,
+    started: SystemTime,
+}
+
+impl TraceContext {
+    /// Continues the trace from the request's `traceparent`, or starts a new
+    /// one if there isn't a valid one.
+    pub fn new(req: &HttpRequest) -> Self {
+        let parent = req
+            .headers()
+            .get("traceparent")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+
+        let (trace_id, parent_span_id, flags) = match parent {
+            Some((trace_id, span_id, flags)) => (trace_id, Some(span_id), flags),
+            None => (random_hex(16), None, "01".to_string()),
+        };
+
+        TraceContext {
+            trace_id,
+            span_id: random_hex(8),
+            parent_span_id,
+            flags,
+            started: SystemTime::now(),
+        }
+    }
+
+    /// Sent to the backend, so that its spans end up under ours.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+/// `(trace ID, parent ID, flags)` from a W3C `traceparent` header.
+fn parse_traceparent(header: &str) -> Option<(String, String, String)> {
+    let parts = header.trim().split('-').collect::<Vec<_>>();
+
+    // Later versions may add fields, but have to keep these first
+    let (version, trace_id, span_id, flags) = match parts[..] {
+        [version, trace_id, span_id, flags] => (version, trace_id, span_id, flags),
+        [version, trace_id, span_id, flags, ..] if version != "00" => {
+            (version, trace_id, span_id, flags)
+        }
+        _ => return None,
+    };
+
+    let hex = |s: &str, len: usize| {
+        s.len() == len
+            && s.chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    };
+    let zero = |s: &str| s.chars().all(|c| c == '0');
+
+    let valid = hex(version, 2)
+        && version != "ff"
+        && hex(trace_id, 32)
+        && !zero(trace_id)
+        && hex(span_id, 16)
+        && !zero(span_id)
+        && hex(flags, 2);
+
+    valid.then(|| (trace_id.to_string(), span_id.to_string(), flags.to_string()))
+}
+
+fn random_hex(bytes: usize) -> String {
+    (0..bytes)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect()
+}
+
+/// Collects a span per proxied request and exports them as OTLP/HTTP JSON.
+pub struct Tracer {
+    queue: Arc<Mutex<Vec<Value>>>,
+    /// Spans that didn't fit in the queue since the last export
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Tracer {
+    /// Starts exporting to `endpoint` in the background.  Has to be called
+    /// from within the runtime.
+    pub fn new(endpoint: String) -> Self {
+        let queue = Arc::new(Mutex::new(Vec::new()));
+        let dropped = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(export(endpoint, queue.clone(), dropped.clone()));
+
+        Tracer { queue, dropped }
+    }
+
+    /// Queues the span for a request that's been answered with `status`.
+    pub fn finish(
+        &self,
+        ctx: &RequestContext,
+        trace: &TraceContext,
+        status: StatusCode,
+        request_bytes: Option<u64>,
+        response_bytes: Option<u64>,
+    ) {
+        let start = nanos(trace.started);
+        let end = start + ctx.started.elapsed().as_nanos();
+
+        let mut attributes = vec![
+            string_attribute("http.request.method", ctx.method.as_str()),
+            string_attribute("server.address", &ctx.host),
+            string_attribute("url.path", &ctx.path),
+            int_attribute("http.response.status_code", status.as_u16().into()),
+            string_attribute("dumbrouter.request_id", &ctx.id),
+        ];
+
+        if let Some(service) = &ctx.service {
+            attributes.push(string_attribute("dumbrouter.service", service));
+        }
+        if let Some(backend) = &ctx.backend {
+            attributes.push(string_attribute("dumbrouter.backend", backend));
+        }
+        if let Some(bytes) = request_bytes {
+            attributes.push(int_attribute("http.request.body.size", bytes));
+        }
+        if let Some(bytes) = response_bytes {
+            attributes.push(int_attribute("http.response.body.size", bytes));
+        }
+
+        let mut span = json!({
+            "traceId": trace.trace_id,
+            "spanId": trace.span_id,
+            "name": format!("{} {}", ctx.method, ctx.service.as_deref().unwrap_or("-")),
+            // SERVER
+            "kind": 2,
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": attributes,
+            // ERROR for anything we (or the backend) got wrong, UNSET otherwise
+            "status": { "code": if status.is_server_error() { 2 } else { 0 } },
+        });
+
+        if let Some(parent) = &trace.parent_span_id {
+            span["parentSpanId"] = json!(parent);
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() < MAX_QUEUED_SPANS {
+            queue.push(span);
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Sends whatever's been queued every `EXPORT_INTERVAL`, forever.
+async fn export(endpoint: String, queue: Arc<Mutex<Vec<Value>>>, dropped: Arc<AtomicUsize>) {
+    let client = reqwest::Client::builder()
+        .timeout(EXPORT_TIMEOUT)
+        .build()
+        .expect("OTLP client can always be built");
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| "dumbrouter".to_string());
+    let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        // Once per export rather than once per span, or the log would be
+        // flooded just when the collector's already struggling
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log!("WARN: Dropped {dropped} spans, {MAX_QUEUED_SPANS} were already waiting for {endpoint}");
+        }
+
+        let spans = std::mem::take(&mut *queue.lock().unwrap());
+        if spans.is_empty() {
+            continue;
+        }
+        let count = spans.len();
+
+        let payload = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [string_attribute("service.name", &service_name)],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "dumbrouter", "version": VERSION },
+                    "spans": spans,
+                }],
+            }],
+        });
+
+        let res = client.post(&endpoint).json(&payload).send().await;
+
+        if let Err(err) = res.and_then(|res| res.error_for_status()) {
+            log!("WARN: Can't export {count} spans to {endpoint}: {err}");
+        }
+    }
+}
+
+fn nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+// OTLP JSON wants 64-bit integers as strings
+fn int_attribute(key: &str, value: u64) -> Value {
+    json!({ "key": key, "value": { "intValue": value.to_string() } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn traceparents_are_parsed() {
+        let header = format!("00-{TRACE_ID}-{SPAN_ID}-01");
+        assert_eq!(
+            parse_traceparent(&header),
+            Some((TRACE_ID.to_string(), SPAN_ID.to_string(), "01".to_string()))
+        );
+
+        // Later versions can have more to say
+        let header = format!("01-{TRACE_ID}-{SPAN_ID}-01-whatever");
+        assert!(parse_traceparent(&header).is_some());
+    }
+
+    #[test]
+    fn bad_traceparents_are_ignored() {
+        let zeroes = "0".repeat(32);
+        for header in [
+            format!("00-{zeroes}-{SPAN_ID}-01"),
+            format!("00-{TRACE_ID}-0000000000000000-01"),
+            format!("ff-{TRACE_ID}-{SPAN_ID}-01"),
+            format!("00-{}-{SPAN_ID}-01", TRACE_ID.to_uppercase()),
+            format!("00-{TRACE_ID}-{}-01", SPAN_ID.to_uppercase()),
+            format!("00-{TRACE_ID}-{SPAN_ID}-01-whatever"),
+            format!("00-{TRACE_ID}-{SPAN_ID}"),
+            format!("00-{TRACE_ID}1-{SPAN_ID}-01"),
+            "".to_string(),
+        ] {
+            assert_eq!(parse_traceparent(&header), None, "{header}");
+        }
+    }
+
+    #[test]
+    fn traces_are_continued_or_started() {
+        let header = format!("00-{TRACE_ID}-{SPAN_ID}-00");
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("traceparent", header))
+            .to_http_request();
+        let trace = TraceContext::new(&req);
+        assert_eq!(trace.trace_id, TRACE_ID);
+        assert_eq!(trace.parent_span_id.as_deref(), Some(SPAN_ID));
+        assert_ne!(trace.span_id, SPAN_ID);
+        assert_eq!(
+            trace.traceparent(),
+            format!("00-{TRACE_ID}-{}-00", trace.span_id)
+        );
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let trace = TraceContext::new(&req);
+        assert!(parse_traceparent(&trace.traceparent()).is_some());
+        assert_eq!(trace.parent_span_id, None);
+        assert_ne!(trace.trace_id, TraceContext::new(&req).trace_id);
+    }
+
+    #[actix_web::test]
+    async fn spans_past_the_limit_are_counted_as_dropped() {
+        // The exporter doesn't get to run (and empty the queue) until the test
+        // awaits something
+        let tracer = Tracer::new("http://127.0.0.1:9/".to_string());
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let ctx = RequestContext::new(&req, "foo.example.com");
+        let trace = TraceContext::new(&req);
+
+        for _ in 0..MAX_QUEUED_SPANS + 3 {
+            tracer.finish(&ctx, &trace, StatusCode::OK, None, None);
+        }
+        assert_eq!(tracer.queue.lock().unwrap().len(), MAX_QUEUED_SPANS);
+        assert_eq!(tracer.dropped.load(Ordering::Relaxed), 3);
+    }
+}